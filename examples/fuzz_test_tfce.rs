@@ -3,6 +3,7 @@ extern crate libtfce;
 use libtfce::field::{generate_2d8c_field, set_random_values};
 use libtfce::tfce;
 use libtfce::approximate_tfce;
+use libtfce::tfce::approximate_tfce::Discretisation;
 
 fn main() {
     for x in 29830..29900 {
@@ -12,7 +13,7 @@ fn main() {
         set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, x]);
 
         let mut approx_voxels = voxels.clone();
-        approximate_tfce(&mut approx_voxels, 10000);
+        approximate_tfce(&mut approx_voxels, 2.0/3.0, 2.0, 0.0, Discretisation::Steps(10000));
 
         let mut exact_voxels = voxels.clone();
         tfce(&mut exact_voxels, 2.0/3.0, 2.0);
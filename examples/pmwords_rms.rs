@@ -2,8 +2,10 @@ extern crate libtfce;
 
 use libtfce::field::generate_1d_field;
 use libtfce::tfce;
-use libtfce::ttest;
+use libtfce::ttest::TTest;
 use libtfce::permutation;
+use libtfce::permutation::{Tail, Permutations};
+use libtfce::enhancement::Tfce;
 use libtfce::read_data_file;
 use std::env;
 
@@ -14,16 +16,15 @@ fn main() {
     let mut voxels = generate_1d_field(a[0].len());
 
     let result = permutation::get_periods(permutation::significant_indices(
-        &permutation::run_permutation(
-            &a, &b, 1000,
-            &mut |a, b| {
-                for (v, tv) in voxels.iter_mut().zip(::ttest::ttest_rel_vec(&a, &b).into_iter()) {
-                    v.value = tv.abs();
-                }
-                tfce(&mut voxels, 1.9, 0.0);
-                voxels.iter().map(|v| v.tfce_value).collect()
-            }
-        )
+        &tfce::run_permutation(
+            &mut voxels,
+            &a, &b,
+            &TTest::Paired,
+            &Permutations::SignFlips(permutation::generate_sign_flips(a.len(), 1000, &permutation::DEFAULT_SEED)),
+            &Tfce { e: 1.9, h: 0.0 },
+            Tail::TwoSided,
+            0.05
+        ).significant
     ));
 
     eprintln!("result: {:?}", result);
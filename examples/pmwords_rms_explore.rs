@@ -2,6 +2,9 @@ extern crate libtfce;
 
 use libtfce::field::generate_1d_field;
 use libtfce::read_data_file;
+use libtfce::permutation;
+use libtfce::permutation::{Tail, Permutations};
+use libtfce::ttest::TTest;
 use std::env;
 
 fn main() {
@@ -12,9 +15,12 @@ fn main() {
 
     libtfce::explore_tfce_permutation(
         &a, &b,
-        1000,
+        &TTest::Paired,
+        &Permutations::SignFlips(permutation::generate_sign_flips(a.len(), 1000, &permutation::DEFAULT_SEED)),
         &mut voxels,
         0.0, 0.02, 2.0,
-        0.0, 0.02, 2.0
+        0.0, 0.02, 2.0,
+        Tail::TwoSided,
+        0.05
     );
 }
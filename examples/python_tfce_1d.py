@@ -35,8 +35,8 @@ subprocess.call([
     "--type", "1d",
     "--input-file", "data.bin",
     "--output-file", "result.bin",
-    "--k", "0.666",
-    "--e", "2.0",
+    "-e", "0.666",
+    "-h", "2.0",
     "--permutation-count", "1000"
 ])
 
@@ -53,4 +53,31 @@ result_file.close()
 # and 0 where it is not
 print(result)
 
+# call libtfce again for FWER-corrected p-values and their Monte Carlo standard errors
+subprocess.call([
+    "target/release/libtfce",
+    "--type", "1d",
+    "--input-file", "data.bin",
+    "--output-file", "p_values.bin",
+    "--output-p-values",
+    "--output-standard-errors", "standard_errors.bin",
+    "-e", "0.666",
+    "-h", "2.0",
+    "--permutation-count", "1000"
+])
+
+# both files contain value count followed by values as doubles
+def read_doubles(filename):
+    f = open(filename, "rb")
+    size = array("I", [])
+    size.fromfile(f, 1)
+    values = array("d", [])
+    values.fromfile(f, size[0])
+    f.close()
+    return values.tolist()
+
+p_values = read_doubles("p_values.bin")
+standard_errors = read_doubles("standard_errors.bin")
+print(min(p_values), max(standard_errors))
+
 #
\ No newline at end of file
@@ -3,6 +3,7 @@ extern crate libtfce;
 use libtfce::field::{generate_2d8c_field, set_random_values};
 use libtfce::tfce;
 use libtfce::approximate_tfce;
+use libtfce::tfce::approximate_tfce::Discretisation;
 
 fn main() {
     let n = 20;
@@ -21,7 +22,7 @@ fn main() {
     );
 
     let mut approx_voxels = voxels.clone();
-    approximate_tfce(&mut approx_voxels, 5000);
+    approximate_tfce(&mut approx_voxels, 2.0/3.0, 2.0, 0.0, Discretisation::Steps(5000));
     let approx_data = approx_voxels.iter().map(|v| v.tfce_value).collect::<Vec<f64>>();
     println!("pyplot.subplot2grid((3,2), (0, 1))");
     println!("pyplot.title('approximate')");
@@ -3,8 +3,10 @@ extern crate rand;
 
 use libtfce::field::{generate_1d_field};
 use libtfce::tfce;
-use libtfce::ttest;
+use libtfce::ttest::TTest;
 use libtfce::permutation;
+use libtfce::permutation::{Tail, Permutations};
+use libtfce::enhancement::Tfce;
 use libtfce::prob_bin_search::{probabilistic_binary_search, NormDistr};
 use rand::{Rng, StdRng, SeedableRng};
 
@@ -44,16 +46,15 @@ fn main() {
                     }
 
                     let result = permutation::significant_indices(
-                        &permutation::run_permutation(
-                            &a, &b, 200,
-                            &mut |a, b| {
-                                for (v, tv) in voxels.iter_mut().zip(::ttest::ttest_rel_vec(&a, &b).into_iter()) {
-                                    v.value = tv.abs();
-                                }
-                                tfce(&mut voxels, 2.0/3.0, 2.0);
-                                voxels.iter().map(|v| v.tfce_value).collect()
-                            }
-                        )
+                        &tfce::run_permutation(
+                            &mut voxels,
+                            &a, &b,
+                            &TTest::Paired,
+                            &Permutations::SignFlips(permutation::generate_sign_flips(nsubj, 200, &permutation::DEFAULT_SEED)),
+                            &Tfce { e: 2.0/3.0, h: 2.0 },
+                            Tail::TwoSided,
+                            0.05
+                        ).significant
                     );
 
                     result.is_empty()
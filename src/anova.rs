@@ -0,0 +1,212 @@
+use ::statistic::Statistic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    MainA,
+    MainB,
+    Interaction
+}
+
+// within-subject conditions are ordered with factor A levels major:
+// condition index = level_a * levels_b + level_b
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RmAnova {
+    pub levels_a: usize,
+    pub levels_b: usize,
+    pub effect: Effect
+}
+
+impl RmAnova {
+    pub fn one_way(levels: usize) -> RmAnova {
+        RmAnova { levels_a: levels, levels_b: 1, effect: Effect::MainA }
+    }
+
+    pub fn two_way(levels_a: usize, levels_b: usize, effect: Effect) -> RmAnova {
+        RmAnova { levels_a, levels_b, effect }
+    }
+
+    pub fn condition_count(&self) -> usize {
+        self.levels_a * self.levels_b
+    }
+
+    // F statistic for every voxel, data is indexed by subject and condition
+    pub fn compute(&self, data: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        let nsubj = data.len();
+        let la = self.levels_a;
+        let lb = self.levels_b;
+        assert!(data.iter().all(|conditions| conditions.len() == la * lb), "every subject must have {} conditions", la * lb);
+        let n = data[0][0].len();
+        let ns = nsubj as f64;
+
+        let (df_effect, df_error) =
+            match self.effect {
+                Effect::MainA => (la - 1, (la - 1) * (nsubj - 1)),
+                Effect::MainB => (lb - 1, (lb - 1) * (nsubj - 1)),
+                Effect::Interaction => ((la - 1) * (lb - 1), (la - 1) * (lb - 1) * (nsubj - 1))
+            };
+
+        let mut subj_mean = vec![0.0; nsubj];
+        let mut subj_a_mean = vec![vec![0.0; la]; nsubj];
+        let mut subj_b_mean = vec![vec![0.0; lb]; nsubj];
+        let mut cell_mean = vec![vec![0.0; lb]; la];
+        let mut a_mean = vec![0.0; la];
+        let mut b_mean = vec![0.0; lb];
+
+        let mut result = Vec::with_capacity(n);
+        let mut i = 0;
+        while i < n {
+            let mut grand_mean = 0.0;
+            for ia in 0..la {
+                a_mean[ia] = 0.0;
+                for ib in 0..lb {
+                    cell_mean[ia][ib] = 0.0;
+                }
+            }
+            for ib in 0..lb {
+                b_mean[ib] = 0.0;
+            }
+
+            for s in 0..nsubj {
+                subj_mean[s] = 0.0;
+                for ia in 0..la {
+                    subj_a_mean[s][ia] = 0.0;
+                }
+                for ib in 0..lb {
+                    subj_b_mean[s][ib] = 0.0;
+                }
+                for ia in 0..la {
+                    for ib in 0..lb {
+                        let v = data[s][ia * lb + ib][i];
+                        subj_mean[s] += v;
+                        subj_a_mean[s][ia] += v;
+                        subj_b_mean[s][ib] += v;
+                        cell_mean[ia][ib] += v;
+                    }
+                }
+                grand_mean += subj_mean[s];
+                subj_mean[s] /= (la * lb) as f64;
+                for ia in 0..la {
+                    subj_a_mean[s][ia] /= lb as f64;
+                }
+                for ib in 0..lb {
+                    subj_b_mean[s][ib] /= la as f64;
+                }
+            }
+            grand_mean /= ns * ((la * lb) as f64);
+            for ia in 0..la {
+                for ib in 0..lb {
+                    cell_mean[ia][ib] /= ns;
+                    a_mean[ia] += cell_mean[ia][ib] / (lb as f64);
+                    b_mean[ib] += cell_mean[ia][ib] / (la as f64);
+                }
+            }
+
+            let mut ss_effect = 0.0;
+            let mut ss_error = 0.0;
+            match self.effect {
+                Effect::MainA => {
+                    for ia in 0..la {
+                        ss_effect += ns * (lb as f64) * (a_mean[ia] - grand_mean).powi(2);
+                        for s in 0..nsubj {
+                            ss_error += (lb as f64) * (subj_a_mean[s][ia] - subj_mean[s] - a_mean[ia] + grand_mean).powi(2);
+                        }
+                    }
+                },
+                Effect::MainB => {
+                    for ib in 0..lb {
+                        ss_effect += ns * (la as f64) * (b_mean[ib] - grand_mean).powi(2);
+                        for s in 0..nsubj {
+                            ss_error += (la as f64) * (subj_b_mean[s][ib] - subj_mean[s] - b_mean[ib] + grand_mean).powi(2);
+                        }
+                    }
+                },
+                Effect::Interaction => {
+                    for ia in 0..la {
+                        for ib in 0..lb {
+                            ss_effect += ns * (cell_mean[ia][ib] - a_mean[ia] - b_mean[ib] + grand_mean).powi(2);
+                            for s in 0..nsubj {
+                                ss_error += (
+                                    data[s][ia * lb + ib][i]
+                                        - subj_a_mean[s][ia] - subj_b_mean[s][ib] - cell_mean[ia][ib]
+                                        + subj_mean[s] + a_mean[ia] + b_mean[ib] - grand_mean
+                                ).powi(2);
+                            }
+                        }
+                    }
+                }
+            }
+
+            let f_value = (ss_effect / (df_effect as f64)) / (ss_error / (df_error as f64));
+            result.push(if f_value.is_nan() { 0.0 } else { f_value });
+
+            i += 1;
+        }
+        result
+    }
+}
+
+// groups are subjects, each containing all conditions
+impl Statistic for RmAnova {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        RmAnova::compute(self, groups)
+    }
+}
+
+pub fn rm_anova_vec(data: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+    RmAnova::one_way(data[0].len()).compute(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ttest::ttest_rel;
+
+    fn as_subjects(conditions: &Vec<Vec<f64>>) -> Vec<Vec<Vec<f64>>> {
+        (0..conditions[0].len()).map(|s| conditions.iter().map(|c| vec![c[s]]).collect()).collect()
+    }
+
+    fn compute(anova: RmAnova, data: &Vec<Vec<Vec<f64>>>) -> f64 {
+        anova.compute(&data.iter().map(|conditions| conditions.iter().collect()).collect())[0]
+    }
+
+    #[test]
+    fn test_rm_anova_two_conditions() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645];
+        let b = vec![ 0.44289209,  0.60141457,  0.06801757,  0.23473256,  0.41713896];
+        let data = as_subjects(&vec![a.clone(), b.clone()]);
+        assert!((compute(RmAnova::one_way(2), &data) - ttest_rel(&a, &b).powi(2)).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_rm_anova_one_way() {
+        let data = as_subjects(&vec![
+            vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645],
+            vec![ 0.44289209,  0.60141457,  0.06801757,  0.23473256,  0.41713896],
+            vec![ 0.30417776,  0.88567478,  0.78889515,  0.5128209 ,  0.87737296]
+        ]);
+        assert!((compute(RmAnova::one_way(3), &data) - 2.5190653077672973).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_rm_anova_two_way() {
+        // 2x2 design: interaction F equals squared paired t-test of difference of differences
+        let c = vec![
+            vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645],
+            vec![ 0.44289209,  0.60141457,  0.06801757,  0.23473256,  0.41713896],
+            vec![ 0.30417776,  0.88567478,  0.78889515,  0.5128209 ,  0.87737296],
+            vec![ 0.06973489,  0.10810649,  0.14355822,  0.1778686 ,  0.43980159]
+        ];
+        let data = as_subjects(&c);
+        let dd_a = (0..5).map(|s| c[0][s] - c[1][s]).collect::<Vec<f64>>();
+        let dd_b = (0..5).map(|s| c[2][s] - c[3][s]).collect::<Vec<f64>>();
+        assert!((compute(RmAnova::two_way(2, 2, Effect::Interaction), &data) - ttest_rel(&dd_a, &dd_b).powi(2)).abs() < 1e-7);
+
+        let main_a1 = (0..5).map(|s| (c[0][s] + c[1][s]) / 2.0).collect::<Vec<f64>>();
+        let main_a2 = (0..5).map(|s| (c[2][s] + c[3][s]) / 2.0).collect::<Vec<f64>>();
+        assert!((compute(RmAnova::two_way(2, 2, Effect::MainA), &data) - ttest_rel(&main_a1, &main_a2).powi(2)).abs() < 1e-7);
+
+        let main_b1 = (0..5).map(|s| (c[0][s] + c[2][s]) / 2.0).collect::<Vec<f64>>();
+        let main_b2 = (0..5).map(|s| (c[1][s] + c[3][s]) / 2.0).collect::<Vec<f64>>();
+        assert!((compute(RmAnova::two_way(2, 2, Effect::MainB), &data) - ttest_rel(&main_b1, &main_b2).powi(2)).abs() < 1e-7);
+    }
+}
@@ -0,0 +1,201 @@
+use ::statistic::Statistic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Correlation {
+    Pearson,
+    Spearman
+}
+
+impl Correlation {
+    // correlation of every voxel with covariate across subjects, converted to t value
+    pub fn compute(&self, data: &Vec<&Vec<f64>>, covariate: &Vec<f64>) -> Vec<f64> {
+        match *self {
+            Correlation::Pearson => pearson_vec(data, covariate),
+            Correlation::Spearman => spearman_vec(data, covariate)
+        }
+    }
+}
+
+// correlation of single group of subjects with covariate; permuting subjects
+// is equivalent to shuffling covariate
+#[derive(Debug, Clone)]
+pub struct CovariateCorrelation {
+    correlation: Correlation,
+    covariate: Vec<f64>
+}
+
+impl CovariateCorrelation {
+    pub fn new(correlation: Correlation, covariate: Vec<f64>) -> CovariateCorrelation {
+        // covariate is ranked once instead of in every permutation
+        let covariate =
+            match correlation {
+                Correlation::Pearson => covariate,
+                Correlation::Spearman => rank(&covariate)
+            };
+        CovariateCorrelation { correlation, covariate }
+    }
+}
+
+// spearman ranks voxel data of every permutation, since statistic can not
+// tell whether it was given data that is already ranked
+impl Statistic for CovariateCorrelation {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        match self.correlation {
+            Correlation::Pearson => pearson_vec(&groups[0], &self.covariate),
+            Correlation::Spearman => pearson_vec(&rank_vec(&groups[0]).iter().collect(), &self.covariate)
+        }
+    }
+}
+
+pub fn pearson(a: &Vec<f64>, covariate: &Vec<f64>) -> f64 {
+    let a = a.iter().map(|&v| vec![v]).collect::<Vec<Vec<f64>>>();
+    pearson_vec(&a.iter().collect(), covariate)[0]
+}
+
+pub fn pearson_vec(data: &Vec<&Vec<f64>>, covariate: &Vec<f64>) -> Vec<f64> {
+    let nsubj = data.len();
+    assert!(nsubj >= 3, "correlation test requires at least 3 subjects, got {}", nsubj);
+    assert!(covariate.len() == nsubj, "covariate has {} values, but there are {} subjects", covariate.len(), nsubj);
+    let n = data[0].len();
+    let ns = nsubj as f64;
+
+    let c_mean = covariate.iter().sum::<f64>() / ns;
+    let c = covariate.iter().map(|&v| v - c_mean).collect::<Vec<f64>>();
+    let c_ss = c.iter().map(|&v| v * v).sum::<f64>();
+
+    let mut result = Vec::with_capacity(n);
+    let mut i = 0;
+    while i < n {
+        let mut sum = 0.0;
+        let mut sum2 = 0.0;
+        let mut sum_c = 0.0;
+        let mut s = 0;
+        while s < nsubj {
+            let v = unsafe { *data.get_unchecked(s).get_unchecked(i) };
+            sum += v;
+            sum2 += v*v;
+            sum_c += v * c[s];
+            s += 1;
+        }
+        let r = sum_c / ((sum2 - sum*sum/ns) * c_ss).sqrt();
+        let t_value = r_to_t(r, nsubj);
+        result.push(if t_value.is_nan() { 0.0 } else { t_value });
+
+        i += 1;
+    }
+    result
+}
+
+pub fn spearman(a: &Vec<f64>, covariate: &Vec<f64>) -> f64 {
+    let a = a.iter().map(|&v| vec![v]).collect::<Vec<Vec<f64>>>();
+    spearman_vec(&a.iter().collect(), covariate)[0]
+}
+
+pub fn spearman_vec(data: &Vec<&Vec<f64>>, covariate: &Vec<f64>) -> Vec<f64> {
+    let ranks = rank_vec(data);
+    pearson_vec(&ranks.iter().collect(), &rank(covariate))
+}
+
+// largest |r| converted to t value; perfect correlation, common for spearman
+// with few subjects, would give infinite t and make max statistic useless.
+// Clamped t is still about 7e4 * sqrt(n - 2), far above any imperfect
+// correlation, so a permutation with perfect correlation anywhere has a max
+// statistic that no observed map short of perfect correlation can reach
+const MAX_R: f64 = 1.0 - 1e-10;
+
+// t value with n - 2 degrees of freedom
+pub fn r_to_t(r: f64, nsubj: usize) -> f64 {
+    assert!(nsubj >= 3, "t value of correlation requires at least 3 subjects, got {}", nsubj);
+    let r = r.max(-MAX_R).min(MAX_R);
+    r * (((nsubj - 2) as f64) / (1.0 - r*r)).sqrt()
+}
+
+// ranks starting from 1, ties get average rank
+pub fn rank(x: &Vec<f64>) -> Vec<f64> {
+    let mut order = (0..x.len()).collect::<Vec<usize>>();
+    order.sort_by(|&i, &j| x[i].partial_cmp(&x[j]).expect("cannot rank NaN values"));
+
+    let mut ranks = vec![0.0; x.len()];
+    let mut i = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && x[order[j + 1]] == x[order[i]] {
+            j += 1;
+        }
+        let r = (i + j) as f64 / 2.0 + 1.0;
+        for k in i..(j + 1) {
+            ranks[order[k]] = r;
+        }
+        i = j + 1;
+    }
+    ranks
+}
+
+// ranks every voxel across subjects
+pub fn rank_vec(data: &Vec<&Vec<f64>>) -> Vec<Vec<f64>> {
+    let n = data[0].len();
+    let mut ranks = vec![Vec::with_capacity(n); data.len()];
+    for i in 0..n {
+        let r = rank(&data.iter().map(|subj| subj[i]).collect());
+        for (s, v) in r.into_iter().enumerate() {
+            ranks[s].push(v);
+        }
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pearson() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645,  0.30417776];
+        let c = vec![3.0, 2.5, 1.0, 2.0, 2.5, 1.5];
+        assert!((pearson(&a, &c) - 11.480219747464549).abs() < 1e-7);
+        let negated = a.iter().map(|v| -v).collect();
+        assert!((pearson(&negated, &c) + 11.480219747464549).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_spearman() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645,  0.30417776];
+        let c = vec![3.0, 2.5, 1.0, 2.0, 2.5, 1.5];
+        assert_eq!(rank(&c), vec![6.0, 4.5, 1.0, 3.0, 4.5, 2.0]);
+        assert!((spearman(&a, &c) - 11.6619037896906).abs() < 1e-7);
+        // monotonic transform does not change spearman correlation
+        let cubed = a.iter().map(|v| v * v * v).collect();
+        assert!((spearman(&cubed, &c) - 11.6619037896906).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_perfect_correlation_is_finite() {
+        let c = vec![3.0, 2.5, 1.0, 2.0, 1.5];
+        let linear = c.iter().map(|v| 2.0 * v + 1.0).collect();
+        let monotone = c.iter().map(|v| v * v * v).collect::<Vec<f64>>();
+        let t = r_to_t(MAX_R, c.len());
+        assert!(t.is_finite() && t > 0.0);
+        assert!((pearson(&linear, &c) - t).abs() < 1e-3 * t);
+        assert_eq!(spearman(&monotone, &c), t);
+        assert_eq!(spearman(&monotone.iter().map(|v| -v).collect(), &c), -t);
+        // rounding may push |r| above 1
+        assert_eq!(r_to_t(1.0 + 1e-15, c.len()), t);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_pearson_requires_three_subjects() {
+        pearson(&vec![1.0, 2.0], &vec![2.0, 1.0]);
+    }
+
+    #[test]
+    fn test_covariate_correlation() {
+        let data = vec![vec![0.78, 0.1], vec![0.70, 0.4], vec![0.04, 0.3], vec![0.47, 0.9], vec![0.71, 0.2]];
+        let c = vec![3.0, 2.5, 1.0, 2.0, 1.5];
+        let spearman = CovariateCorrelation::new(Correlation::Spearman, c.clone());
+        assert_eq!(spearman.compute(&vec![data.iter().collect()]), spearman_vec(&data.iter().collect(), &c));
+
+        let pearson = CovariateCorrelation::new(Correlation::Pearson, c.clone());
+        assert_eq!(pearson.compute(&vec![data.iter().collect()]), pearson_vec(&data.iter().collect(), &c));
+    }
+}
@@ -0,0 +1,209 @@
+use ::voxel::Voxel;
+use ::permutation::Tail;
+use ::tfce;
+use ::tfce::approximate_tfce::get_clusters;
+use ::tfce::height_grid::{HeightGrid, height_grid_tfce};
+use ::tfce::approximate_tfce::Discretisation;
+use ::tfce::anisotropic::{DimensionExtent, anisotropic_tfce};
+
+// transforms statistic map of every permutation before its max is taken.
+// Positive and negative parts are enhanced separately as selected by tail,
+// enhanced negative part stays negative
+pub trait Enhancement {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tfce {
+    pub e: f64,
+    pub h: f64
+}
+
+impl Enhancement for Tfce {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64> {
+        tfce::enhance_statistic(voxels, statistic, tail, self.e, self.h)
+    }
+}
+
+// discretised tfce on fixed height grid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightGridTfce {
+    pub e: f64,
+    pub h: f64,
+    pub grid: HeightGrid
+}
+
+impl Enhancement for HeightGridTfce {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64> {
+        for (v, sv) in voxels.iter_mut().zip(statistic.into_iter()) {
+            v.value = sv;
+        }
+        tfce::signed_enhancement(voxels, tail, &mut |voxels| height_grid_tfce(voxels, self.e, self.h, self.grid));
+        voxels.iter().map(|v| v.tfce_value).collect()
+    }
+}
+
+// discretised tfce with separate extent exponent and scale for every
+// dimension of links, e.g. space and time
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnisotropicTfce {
+    pub extents: Vec<DimensionExtent>,
+    pub h: f64,
+    pub h0: f64,
+    pub discretisation: Discretisation
+}
+
+impl Enhancement for AnisotropicTfce {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64> {
+        for (v, sv) in voxels.iter_mut().zip(statistic.into_iter()) {
+            v.value = sv;
+        }
+        tfce::signed_enhancement(voxels, tail, &mut |voxels| {
+            anisotropic_tfce(voxels, &self.extents, self.h, self.h0, self.discretisation)
+        });
+        voxels.iter().map(|v| v.tfce_value).collect()
+    }
+}
+
+// every voxel of supra-threshold cluster gets sum of statistic values in cluster
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterMass {
+    pub threshold: f64
+}
+
+impl Enhancement for ClusterMass {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64> {
+        enhance_clusters(voxels, statistic, tail, self.threshold, &|cluster, values| {
+            cluster.iter().map(|&i| values[i]).sum()
+        })
+    }
+}
+
+// every voxel of supra-threshold cluster gets extent of cluster, sum of voxel weights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterExtent {
+    pub threshold: f64
+}
+
+impl Enhancement for ClusterExtent {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64> {
+        let weights = voxels.iter().map(|v| v.weight).collect::<Vec<f64>>();
+        enhance_clusters(voxels, statistic, tail, self.threshold, &|cluster, _| {
+            cluster.iter().map(|&i| weights[i]).sum()
+        })
+    }
+}
+
+// no enhancement, max of statistic map gives voxelwise max-T correction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxT;
+
+impl Enhancement for MaxT {
+    fn enhance(&self, voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail) -> Vec<f64> {
+        for (v, &sv) in voxels.iter_mut().zip(statistic.iter()) {
+            v.value = sv;
+            v.tfce_value =
+                if (sv > 0.0 && tail.includes_positive()) || (sv < 0.0 && tail.includes_negative()) {
+                    sv
+                } else {
+                    0.0
+                };
+        }
+        voxels.iter().map(|v| v.tfce_value).collect()
+    }
+}
+
+// clusters are formed where positive (or negated negative) part of statistic
+// reaches threshold, every voxel of cluster gets its score
+fn enhance_clusters(
+    voxels: &mut Vec<Voxel>,
+    statistic: Vec<f64>,
+    tail: Tail,
+    threshold: f64,
+    score: &Fn(&Vec<usize>, &Vec<f64>) -> f64
+) -> Vec<f64> {
+    assert!(threshold > 0.0, "cluster forming threshold must be positive");
+    let mut enhanced = vec![0.0; voxels.len()];
+
+    for &(sign, included) in [(1.0, tail.includes_positive()), (-1.0, tail.includes_negative())].iter() {
+        if !included {
+            continue;
+        }
+        for (v, &sv) in voxels.iter_mut().zip(statistic.iter()) {
+            v.value = (sign * sv).max(0.0);
+        }
+        let values = voxels.iter().map(|v| v.value).collect::<Vec<f64>>();
+        for cluster in get_clusters(voxels, threshold).into_iter() {
+            let s = score(&cluster, &values);
+            for i in cluster.into_iter() {
+                enhanced[i] += sign * s;
+            }
+        }
+    }
+
+    for ((v, sv), ev) in voxels.iter_mut().zip(statistic.into_iter()).zip(enhanced.iter()) {
+        v.value = sv;
+        v.tfce_value = *ev;
+    }
+    enhanced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::field::generate_1d_field;
+
+    fn statistic() -> Vec<f64> {
+        vec![0.5, 2.0, 3.0, 0.5, -2.5, -2.0, 1.0, 2.5]
+    }
+
+    #[test]
+    fn test_cluster_mass() {
+        let mut voxels = generate_1d_field(8);
+        let enhanced = ClusterMass { threshold: 1.5 }.enhance(&mut voxels, statistic(), Tail::TwoSided);
+        assert_eq!(enhanced, vec![0.0, 5.0, 5.0, 0.0, -4.5, -4.5, 0.0, 2.5]);
+        // voxel values are restored
+        assert_eq!(voxels.iter().map(|v| v.value).collect::<Vec<f64>>(), statistic());
+    }
+
+    #[test]
+    fn test_cluster_extent() {
+        let mut voxels = generate_1d_field(8);
+        let enhanced = ClusterExtent { threshold: 1.5 }.enhance(&mut voxels, statistic(), Tail::Positive);
+        assert_eq!(enhanced, vec![0.0, 2.0, 2.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+        let enhanced = ClusterExtent { threshold: 1.5 }.enhance(&mut voxels, statistic(), Tail::Negative);
+        assert_eq!(enhanced, vec![0.0, 0.0, 0.0, 0.0, -2.0, -2.0, 0.0, 0.0]);
+
+        voxels[1].weight = 0.5;
+        voxels[2].weight = 2.0;
+        let enhanced = ClusterExtent { threshold: 1.5 }.enhance(&mut voxels, statistic(), Tail::Positive);
+        assert_eq!(enhanced, vec![0.0, 2.5, 2.5, 0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_max_t() {
+        let mut voxels = generate_1d_field(8);
+        assert_eq!(MaxT.enhance(&mut voxels, statistic(), Tail::TwoSided), statistic());
+        assert_eq!(
+            MaxT.enhance(&mut voxels, statistic(), Tail::Positive),
+            vec![0.5, 2.0, 3.0, 0.5, 0.0, 0.0, 1.0, 2.5]
+        );
+    }
+
+    #[test]
+    fn test_tfce_enhancement() {
+        let mut voxels = generate_1d_field(8);
+        let enhanced = Tfce { e: 2.0/3.0, h: 2.0 }.enhance(&mut voxels, statistic(), Tail::TwoSided);
+        let expected = tfce::enhance_statistic(&mut generate_1d_field(8), statistic(), Tail::TwoSided, 2.0/3.0, 2.0);
+        assert_eq!(enhanced, expected);
+    }
+
+    #[test]
+    fn test_height_grid_tfce_enhancement() {
+        let mut voxels = generate_1d_field(8);
+        let unscaled = HeightGridTfce { e: 1.0, h: 1.0, grid: HeightGrid::UnscaledSteps { start: 0.0, step: 1.0 } };
+        // heights 0, 1, 2 of both parts, clusters above 1: {1, 2}, {7}, {4, 5}, above 2: {2}, {7}, {4}
+        let enhanced = unscaled.enhance(&mut voxels, statistic(), Tail::TwoSided);
+        assert_eq!(enhanced, vec![0.0, 2.0, 4.0, 0.0, -4.0, -2.0, 0.0, 3.0]);
+    }
+}
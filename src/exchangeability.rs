@@ -0,0 +1,199 @@
+use rand::{Rng, StdRng, SeedableRng};
+use std::fs::File;
+use std::io::Read;
+use std::collections::HashMap;
+use ::permutation::{SignFlips, generate_sign_flips};
+
+// exchangeability blocks of subjects (sign flips) or pooled observations (shuffles),
+// as in FSL PALM. Within-block: members are shuffled only inside their block;
+// sign flips are independent anyway, so within-block flips are unrestricted.
+// Whole-block: blocks of equal size are shuffled as a whole, keeping order of
+// members, and all members of a block are flipped together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExchangeabilityBlocks {
+    // block index of every subject or observation, from 0 to block count
+    pub labels: Vec<usize>,
+    pub whole_block: bool
+}
+
+impl ExchangeabilityBlocks {
+    pub fn within(labels: Vec<usize>) -> ExchangeabilityBlocks {
+        ExchangeabilityBlocks { labels: dense_labels(&labels), whole_block: false }
+    }
+
+    pub fn whole(labels: Vec<usize>) -> ExchangeabilityBlocks {
+        let blocks = ExchangeabilityBlocks { labels: dense_labels(&labels), whole_block: true };
+        let members = blocks.members();
+        assert!(
+            members.iter().all(|m| m.len() == members[0].len()),
+            "whole-block permutation requires all exchangeability blocks to have same size"
+        );
+        blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.labels.iter().max().map_or(0, |&m| m + 1)
+    }
+
+    // indices of members of every block, in original order
+    pub fn members(&self) -> Vec<Vec<usize>> {
+        let mut members = vec![Vec::new(); self.block_count()];
+        for (i, &block) in self.labels.iter().enumerate() {
+            members[block].push(i);
+        }
+        members
+    }
+
+    pub fn generate_sign_flips(&self, n: i32, seed: &[usize]) -> SignFlips {
+        if self.whole_block {
+            let block_flips = generate_sign_flips(self.block_count(), n, seed);
+            SignFlips {
+                flips: block_flips.iter()
+                    .map(|flips| self.labels.iter().map(|&block| flips[block]).collect())
+                    .collect(),
+                exhaustive: block_flips.exhaustive
+            }
+        } else {
+            // no-op of blocks: every subject is flipped independently
+            generate_sign_flips(self.len(), n, seed)
+        }
+    }
+
+    pub fn generate_shuffles(&self, n: i32, seed: &[usize]) -> Vec<Vec<usize>> {
+        let members = self.members();
+        let mut rng = StdRng::from_seed(seed);
+        let mut shuffles = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            let mut order = (0..self.len()).collect::<Vec<usize>>();
+            if self.whole_block {
+                let mut block_order = (0..members.len()).collect::<Vec<usize>>();
+                rng.shuffle(&mut block_order);
+                for (block, &target) in members.iter().zip(block_order.iter()) {
+                    for (&i, &o) in block.iter().zip(members[target].iter()) {
+                        order[i] = o;
+                    }
+                }
+            } else {
+                for block in members.iter() {
+                    let mut shuffled = block.clone();
+                    rng.shuffle(&mut shuffled);
+                    for (&i, &o) in block.iter().zip(shuffled.iter()) {
+                        order[i] = o;
+                    }
+                }
+            }
+            shuffles.push(order);
+        }
+        shuffles
+    }
+
+    pub fn allows_sign_flips(&self, flips: &Vec<bool>) -> bool {
+        flips.len() == self.len() &&
+            (!self.whole_block || self.members().iter().all(|block| block.iter().all(|&i| flips[i] == flips[block[0]])))
+    }
+
+    pub fn allows_shuffle(&self, order: &Vec<usize>) -> bool {
+        if order.len() != self.len() {
+            return false;
+        }
+        if !self.whole_block {
+            return order.iter().enumerate().all(|(i, &o)| self.labels[i] == self.labels[o]);
+        }
+        // every block must be mapped onto some other block, member by member
+        let members = self.members();
+        let mut targets = Vec::with_capacity(members.len());
+        for block in members.iter() {
+            let target = self.labels[order[block[0]]];
+            if !block.iter().zip(members[target].iter()).all(|(&i, &o)| order[i] == o) {
+                return false;
+            }
+            targets.push(target);
+        }
+        targets.sort();
+        targets.dedup();
+        targets.len() == members.len()
+    }
+}
+
+// labels are numbered from 0 in order of first appearance
+fn dense_labels<T: Eq + ::std::hash::Hash + Clone>(labels: &Vec<T>) -> Vec<usize> {
+    let mut index = HashMap::new();
+    labels.iter().map(|label| {
+        let next = index.len();
+        *index.entry(label.clone()).or_insert(next)
+    }).collect()
+}
+
+// exchangeability block file contains one block label per subject or observation,
+// separated by whitespace or commas; labels can be any words or numbers
+pub fn read_exchangeability_blocks(filename: &str) -> Vec<usize> {
+    let mut file = File::open(&filename).expect(&format!("failed to open exchangeability block file {}", &filename));
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect(&format!("failed to read exchangeability block file {}", &filename));
+    let labels = contents.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>();
+    dense_labels(&labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::permutation::DEFAULT_SEED;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_within_block_shuffles() {
+        let blocks = ExchangeabilityBlocks::within(vec![3, 3, 7, 7, 7, 3]);
+        assert_eq!(blocks.labels, vec![0, 0, 1, 1, 1, 0]);
+        let shuffles = blocks.generate_shuffles(20, &DEFAULT_SEED);
+        assert!(shuffles.iter().all(|order| blocks.allows_shuffle(order)));
+        assert!(shuffles.iter().any(|order| order.iter().enumerate().any(|(i, &o)| i != o)));
+        assert!(!blocks.allows_shuffle(&vec![2, 1, 0, 3, 4, 5]));
+    }
+
+    #[test]
+    fn test_whole_block_shuffles() {
+        let blocks = ExchangeabilityBlocks::whole(vec![0, 1, 0, 1, 2, 2]);
+        let shuffles = blocks.generate_shuffles(20, &DEFAULT_SEED);
+        assert!(shuffles.iter().all(|order| blocks.allows_shuffle(order)));
+        // block 0 (observations 0, 2) swapped with block 2 (observations 4, 5)
+        assert!(blocks.allows_shuffle(&vec![4, 1, 5, 3, 0, 2]));
+        // order within block must be kept
+        assert!(!blocks.allows_shuffle(&vec![5, 1, 4, 3, 0, 2]));
+        // within-block shuffle is not a whole-block shuffle
+        assert!(!blocks.allows_shuffle(&vec![2, 1, 0, 3, 4, 5]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_whole_block_sizes() {
+        ExchangeabilityBlocks::whole(vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn test_whole_block_sign_flips() {
+        let blocks = ExchangeabilityBlocks::whole(vec![0, 1, 1, 0]);
+        let sign_flips = blocks.generate_sign_flips(100, &DEFAULT_SEED);
+        // two blocks give four distinct patterns, all enumerated
+        assert_eq!(sign_flips.len(), 4);
+        assert!(sign_flips.iter().all(|flips| blocks.allows_sign_flips(flips)));
+        assert!(!blocks.allows_sign_flips(&vec![true, false, false, false]));
+        assert!(ExchangeabilityBlocks::within(vec![0, 1, 1, 0]).allows_sign_flips(&vec![true, false, false, false]));
+    }
+
+    #[test]
+    fn test_read_exchangeability_blocks() {
+        let filename = env::temp_dir().join("libtfce_test_blocks.txt");
+        let filename = filename.to_str().unwrap();
+        fs::write(filename, "site2\nsite1\nsite2, site3\n").unwrap();
+        assert_eq!(read_exchangeability_blocks(filename), vec![0, 1, 0, 2]);
+        fs::remove_file(filename).unwrap();
+    }
+}
@@ -1,5 +1,5 @@
 use ::fiff;
-use ::voxel::Voxel;
+use ::voxel::{Voxel, Dimension};
 
 use std::fs::File;
 
@@ -76,23 +76,27 @@ fn triangles_to_graph(
 }
 
 pub fn extend_graph_into_time(voxels: Vec<Voxel>, n_times: usize) -> Vec<Voxel> {
+    extend_graph(voxels, n_times, Dimension::Time)
+}
+
+// copies graph n times, linking copies of every voxel in consecutive steps
+// with links labelled by dimension
+pub fn extend_graph(voxels: Vec<Voxel>, n_steps: usize, dimension: Dimension) -> Vec<Voxel> {
     let n_voxels = voxels.len();
-    let mut new_voxels = Vec::with_capacity(n_voxels * n_times);
+    let mut new_voxels = Vec::with_capacity(n_voxels * n_steps);
 
-    for t in 0..n_times {
+    for t in 0..n_steps {
         for n in 0..n_voxels {
             let mut v = voxels[n].clone();
 
-            for l in 0..v.voxel_links.len() {
-                v.voxel_links[l] += t * n_voxels;
-            }
+            v.offset_links(t * n_voxels);
 
-            if t != n_times - 1 {
-                v.voxel_links.push((t + 1) * n_voxels + n);
+            if t != n_steps - 1 {
+                v.add_link((t + 1) * n_voxels + n, dimension);
             }
 
             if t != 0 {
-                v.voxel_links.push((t - 1) * n_voxels + n);
+                v.add_link((t - 1) * n_voxels + n, dimension);
             }
 
             new_voxels.push(v);
@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use ::statistic::Statistic;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Contrast {
+    T(Vec<f64>),
+    F(Vec<Vec<f64>>)
+}
+
+impl Contrast {
+    fn rows(&self) -> Vec<Vec<f64>> {
+        match *self {
+            Contrast::T(ref c) => vec![c.clone()],
+            Contrast::F(ref c) => c.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Glm {
+    design: Vec<Vec<f64>>,
+    contrast: Contrast,
+    // rows of (X'X)^-1 X', gives parameter estimates when applied to data
+    pinv: Vec<Vec<f64>>,
+    // (C (X'X)^-1 C')^-1
+    contrast_cov_inv: Vec<Vec<f64>>,
+    // projections onto nuisance space and onto its orthogonal complement
+    nuisance_hat: Vec<Vec<f64>>,
+    nuisance_residual: Vec<Vec<f64>>
+}
+
+impl Glm {
+    pub fn new(design: Vec<Vec<f64>>, contrast: Contrast) -> Glm {
+        let nobs = design.len();
+        let nparam = design[0].len();
+        assert!(design.iter().all(|row| row.len() == nparam), "design matrix rows must have equal length");
+        assert!(nobs > nparam, "design matrix must have more rows than columns");
+
+        let c = contrast.rows();
+        assert!(c.iter().all(|row| row.len() == nparam), "contrast length must match design matrix column count");
+
+        let xt = transpose(&design);
+        let xtx_inv = invert(&matmul(&xt, &design)).expect("design matrix is rank deficient");
+        let pinv = matmul(&xtx_inv, &xt);
+
+        let contrast_cov_inv =
+            invert(&matmul(&matmul(&c, &xtx_inv), &transpose(&c)))
+            .expect("contrast rows are linearly dependent");
+
+        // split design into effects of interest and nuisance (Smith et al. 2007):
+        // interest = X (X'X)^-1 C' (C (X'X)^-1 C')^-1, nuisance spans the rest of X,
+        // so projection onto nuisance space is H_X - H_interest
+        let interest = matmul(&matmul(&matmul(&design, &xtx_inv), &transpose(&c)), &contrast_cov_inv);
+        let interest_t = transpose(&interest);
+        let interest_hat = matmul(
+            &matmul(&interest, &invert(&matmul(&interest_t, &interest)).unwrap()),
+            &interest_t
+        );
+        let design_hat = matmul(&design, &pinv);
+
+        let mut nuisance_hat = vec![vec![0.0; nobs]; nobs];
+        let mut nuisance_residual = vec![vec![0.0; nobs]; nobs];
+        for i in 0..nobs {
+            for j in 0..nobs {
+                nuisance_hat[i][j] = design_hat[i][j] - interest_hat[i][j];
+                nuisance_residual[i][j] = if i == j { 1.0 } else { 0.0 } - nuisance_hat[i][j];
+            }
+        }
+
+        Glm {
+            design,
+            contrast,
+            pinv,
+            contrast_cov_inv,
+            nuisance_hat,
+            nuisance_residual
+        }
+    }
+
+    pub fn observation_count(&self) -> usize {
+        self.design.len()
+    }
+
+    // t statistic for T contrast, F statistic for F contrast
+    pub fn statistic(&self, y: &Vec<&Vec<f64>>) -> Vec<f64> {
+        let nobs = self.design.len();
+        let nparam = self.pinv.len();
+        let c = self.contrast.rows();
+        let nc = c.len();
+        let n = y[0].len();
+        let dof = (nobs - nparam) as f64;
+
+        let mut result = Vec::with_capacity(n);
+        let mut beta = vec![0.0; nparam];
+        let mut cbeta = vec![0.0; nc];
+        let mut i = 0;
+        while i < n {
+            for p in 0..nparam {
+                beta[p] = 0.0;
+                for s in 0..nobs {
+                    beta[p] += self.pinv[p][s] * y[s][i];
+                }
+            }
+
+            let mut rss = 0.0;
+            for s in 0..nobs {
+                let mut fitted = 0.0;
+                for p in 0..nparam {
+                    fitted += self.design[s][p] * beta[p];
+                }
+                rss += (y[s][i] - fitted).powi(2);
+            }
+            let sigma2 = rss / dof;
+
+            for k in 0..nc {
+                cbeta[k] = 0.0;
+                for p in 0..nparam {
+                    cbeta[k] += c[k][p] * beta[p];
+                }
+            }
+
+            let value =
+                match self.contrast {
+                    Contrast::T(_) => cbeta[0] * self.contrast_cov_inv[0][0].sqrt() / sigma2.sqrt(),
+                    Contrast::F(_) => {
+                        let mut q = 0.0;
+                        for k in 0..nc {
+                            for l in 0..nc {
+                                q += cbeta[k] * self.contrast_cov_inv[k][l] * cbeta[l];
+                            }
+                        }
+                        q / (nc as f64) / sigma2
+                    }
+                };
+            result.push(if value.is_nan() { 0.0 } else { value });
+
+            i += 1;
+        }
+        result
+    }
+
+    // splits data into part explained by nuisance regressors and residuals
+    // of the nuisance-only model, as required by Freedman-Lane permutation
+    pub fn nuisance_split(&self, y: &Vec<Vec<f64>>) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let fitted = matmul_rows(&self.nuisance_hat, y);
+        let residuals = matmul_rows(&self.nuisance_residual, y);
+        (fitted, residuals)
+    }
+}
+
+// single group with one observation per design matrix row
+impl Statistic for Glm {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        self.statistic(&groups[0])
+    }
+}
+
+// matrix file contains one row per line, values separated by whitespace;
+// lines starting with '/' (FSL VEST headers, e.g. /NumWaves) are skipped
+pub fn read_matrix(filename: &str) -> Vec<Vec<f64>> {
+    let file = File::open(&filename).expect(&format!("failed to open matrix file {}", &filename));
+    let mut matrix = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("failed to read matrix file");
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('/') {
+            continue;
+        }
+        matrix.push(
+            line.split_whitespace()
+                .map(|v| v.parse::<f64>().expect(&format!("failed to parse value in matrix file: {}", v)))
+                .collect()
+        );
+    }
+    matrix
+}
+
+fn transpose(m: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    (0..m[0].len()).map(|j| m.iter().map(|row| row[j]).collect()).collect()
+}
+
+fn matmul(a: &Vec<Vec<f64>>, b: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    let n = b[0].len();
+    a.iter().map(|row| {
+        (0..n).map(|j| row.iter().zip(b.iter()).map(|(&x, brow)| x * brow[j]).sum()).collect()
+    }).collect()
+}
+
+// same as matmul, but skips zero coefficients - projection matrices
+// are applied to wide data matrices with one row per observation
+fn matmul_rows(a: &Vec<Vec<f64>>, b: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    let n = b[0].len();
+    a.iter().map(|row| {
+        let mut result = vec![0.0; n];
+        for (&x, brow) in row.iter().zip(b.iter()) {
+            if x != 0.0 {
+                for (r, &v) in result.iter_mut().zip(brow.iter()) {
+                    *r += x * v;
+                }
+            }
+        }
+        result
+    }).collect()
+}
+
+// gauss-jordan elimination with partial pivoting
+fn invert(m: &Vec<Vec<f64>>) -> Option<Vec<Vec<f64>>> {
+    let n = m.len();
+    let scale = m.iter().flat_map(|row| row.iter()).fold(0.0, |s: f64, v| s.max(v.abs()));
+    let mut a = m.clone();
+    let mut inv = (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect::<Vec<Vec<f64>>>();
+
+    for col in 0..n {
+        let mut pivot = col;
+        for row in (col + 1)..n {
+            if a[row][col].abs() > a[pivot][col].abs() {
+                pivot = row;
+            }
+        }
+        if a[pivot][col].abs() <= scale * 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let p = a[col][col];
+        for j in 0..n {
+            a[col][j] /= p;
+            inv[col][j] /= p;
+        }
+        for row in 0..n {
+            if row != col {
+                let f = a[row][col];
+                if f != 0.0 {
+                    for j in 0..n {
+                        a[row][j] -= f * a[col][j];
+                        inv[row][j] -= f * inv[col][j];
+                    }
+                }
+            }
+        }
+    }
+
+    Some(inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ttest::{ttest_1samp, ttest_ind};
+
+    fn as_columns(values: &Vec<f64>) -> Vec<Vec<f64>> {
+        values.iter().map(|&v| vec![v]).collect()
+    }
+
+    #[test]
+    fn test_glm_one_sample() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645];
+        let glm = Glm::new(vec![vec![1.0]; 5], Contrast::T(vec![1.0]));
+        let y = as_columns(&a);
+        let t = glm.statistic(&y.iter().collect());
+        assert!((t[0] - ttest_1samp(&a, 0.0)).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_glm_two_sample() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645];
+        let b = vec![ 0.44289209,  0.60141457,  0.06801757,  0.23473256];
+        let mut design = Vec::new();
+        for _ in 0..a.len() {
+            design.push(vec![1.0, 0.0]);
+        }
+        for _ in 0..b.len() {
+            design.push(vec![0.0, 1.0]);
+        }
+        let y = as_columns(&a.iter().chain(b.iter()).cloned().collect());
+
+        let glm = Glm::new(design.clone(), Contrast::T(vec![1.0, -1.0]));
+        let t = glm.statistic(&y.iter().collect())[0];
+        assert!((t - ttest_ind(&a, &b, true)).abs() < 1e-10);
+
+        let glm = Glm::new(design, Contrast::F(vec![vec![1.0, -1.0]]));
+        let f = glm.statistic(&y.iter().collect())[0];
+        assert!((f - t * t).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_nuisance_split() {
+        let design = vec![
+            vec![1.0, 0.5], vec![1.0, -1.0], vec![1.0, 2.0], vec![1.0, 0.0], vec![1.0, 1.5]
+        ];
+        let y = as_columns(&vec![3.0, 1.0, 4.0, 1.0, 5.0]);
+
+        // testing slope, intercept is nuisance
+        let glm = Glm::new(design, Contrast::T(vec![0.0, 1.0]));
+        let (fitted, residuals) = glm.nuisance_split(&y);
+        for s in 0..5 {
+            assert!((fitted[s][0] - 2.8).abs() < 1e-10);
+            assert!((fitted[s][0] + residuals[s][0] - y[s][0]).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_invert() {
+        let m = vec![vec![4.0, 7.0], vec![2.0, 6.0]];
+        let inv = invert(&m).unwrap();
+        assert!((inv[0][0] - 0.6).abs() < 1e-12);
+        assert!((inv[0][1] - -0.7).abs() < 1e-12);
+        assert!((inv[1][0] - -0.2).abs() < 1e-12);
+        assert!((inv[1][1] - 0.4).abs() < 1e-12);
+        assert!(invert(&vec![vec![1.0, 2.0], vec![2.0, 4.0]]).is_none());
+    }
+}
@@ -1,6 +1,5 @@
 #![feature(test)]
 #![feature(type_ascription)]
-#![feature(box_patterns)]
 
 extern crate rand;
 extern crate test;
@@ -19,7 +18,15 @@ pub mod prob_bin_search;
 pub use self::prob_bin_search::probabilistic_binary_search;
 
 pub mod ttest;
+pub mod glm;
+pub mod anova;
+pub mod correlation;
+pub mod robust;
+pub mod smoothing;
+pub mod statistic;
 pub mod permutation;
+pub mod exchangeability;
+pub mod enhancement;
 mod voxel_priority;
 
 pub mod fiff;
@@ -27,64 +34,59 @@ pub mod freesurfer;
 pub mod stc;
 
 use voxel::Voxel;
+use permutation::{Tail, Permutations};
+use statistic::Statistic;
 use std::fs::File;
+use std::io::Read;
 use byteorder::{LittleEndian, ReadBytesExt};
 
-pub fn explore_tfce_permutation(
+pub fn explore_tfce_permutation<S: Statistic + Sync + ?Sized>(
     a: &Vec<Vec<f64>>,
     b: &Vec<Vec<f64>>,
-    n: i32,
+    statistic: &S,
+    permutations: &Permutations,
     voxels: &mut Vec<Voxel>,
     e_min: f64, e_step: f64, e_max: f64,
     h_min: f64, h_step: f64, h_max: f64,
-    negative: bool,
-    positive: bool
+    tail: Tail,
+    alpha: f64
 ) {
 
+    let mut parameters = Vec::new();
+    let mut e = e_min;
+    while e <= e_max {
+        let mut h = h_min;
+        while h <= h_max {
+            parameters.push((e, h));
+            h += h_step;
+        }
+        e += e_step;
+    }
+
     let n_cpu = num_cpus::get();
     eprintln!("using {} threads to explore TFCE parameters", n_cpu);
     let mut pool = jobsteal::make_pool(n_cpu).unwrap();
 
+    // every thread evaluates its share of parameters from one cluster tree per permutation
+    let chunk_size = ((parameters.len() + n_cpu - 1) / n_cpu).max(1);
     pool.scope(|scope| {
-        let mut e = e_min;
-        while e <= e_max {
-
-            let mut h = h_min;
-            while h <= h_max {
-
-                let mut voxels = voxels.clone();
-
-                scope.submit(move || {
-                    let result = permutation::significant_indices(
-                        &permutation::run_permutation(
-                            a, b, n,
-                            &mut |ap, bp| {
-                                for (v, tv) in voxels.iter_mut().zip(::ttest::ttest_rel_vec(&ap, &bp).into_iter()) {
-                                    if negative {
-                                        v.value = tv.min(0.0).abs();
-                                    } else if positive {
-                                        v.value = tv.max(0.0);
-                                    } else {
-                                        v.value = tv.abs();
-                                    }
-                                }
-                                tfce(&mut voxels, e, h);
-                                voxels.iter().map(|v| v.tfce_value).collect()
-                            }
-                        )
-                    );
-
-                    if result.len() > 0 {
-                        println!("e = {:.4}, h = {:.4}, {:?} significant voxels", e, h, result.len());
+        for chunk in parameters.chunks(chunk_size) {
+            let mut voxels = voxels.clone();
+
+            scope.submit(move || {
+                let results = permutations.run_batch(
+                    a, b, alpha, statistic,
+                    &mut |s| tfce::enhance_statistic_batch(&mut voxels, s, tail, chunk)
+                );
+
+                for (&(e, h), result) in chunk.iter().zip(results.iter()) {
+                    let significant = permutation::significant_indices(&result.significant);
+                    if significant.len() > 0 {
+                        println!("e = {:.4}, h = {:.4}, {:?} significant voxels", e, h, significant.len());
                     }
-                });
-
-                h += h_step;
-            }
-
-            e += e_step;
+                }
+            });
         }
-
     });
 }
 
@@ -95,20 +97,79 @@ pub fn read_data_file(filename: String) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
     let mut file = File::open(&filename).expect(&format!("failed to open input file {}", &filename));
     let subject_count = file.read_u32::<LittleEndian>().unwrap();
     for _ in 0..subject_count {
-        let sa_len = file.read_u32::<LittleEndian>().unwrap();
-        let mut sa = Vec::new();
-        for _ in 0..sa_len {
-            sa.push(file.read_f64::<LittleEndian>().unwrap());
-        }
-        a.push(sa);
+        a.push(read_f64_array(&mut file));
+        b.push(read_f64_array(&mut file));
+    }
 
-        let sb_len = file.read_u32::<LittleEndian>().unwrap();
-        let mut sb = Vec::new();
-        for _ in 0..sb_len {
-            sb.push(file.read_f64::<LittleEndian>().unwrap());
+    (a, b)
+}
+
+// single condition file contains subject count and data arrays, one per subject
+pub fn read_single_condition_data_file(filename: String) -> Vec<Vec<f64>> {
+    let mut file = File::open(&filename).expect(&format!("failed to open input file {}", &filename));
+    read_group(&mut file)
+}
+
+// covariate file contains one value per subject, separated by whitespace
+pub fn read_covariate_file(filename: &str) -> Vec<f64> {
+    read_value_file(filename, "covariate")
+}
+
+// voxel weight file contains one weight per voxel, or per source space vertex
+// for mesh-time data, separated by whitespace
+pub fn read_voxel_weight_file(filename: &str) -> Vec<f64> {
+    read_value_file(filename, "voxel weight")
+}
+
+fn read_value_file(filename: &str, kind: &str) -> Vec<f64> {
+    let mut file = File::open(&filename).expect(&format!("failed to open {} file {}", kind, &filename));
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect(&format!("failed to read {} file {}", kind, &filename));
+    contents.split_whitespace()
+        .map(|v| v.parse::<f64>().expect(&format!("failed to parse value in {} file: {}", kind, v)))
+        .collect()
+}
+
+// multi-condition file contains subject count and condition count,
+// followed by data arrays of every condition for every subject
+pub fn read_multi_condition_data_file(filename: String) -> Vec<Vec<Vec<f64>>> {
+    let mut file = File::open(&filename).expect(&format!("failed to open input file {}", &filename));
+    let subject_count = file.read_u32::<LittleEndian>().unwrap();
+    let condition_count = file.read_u32::<LittleEndian>().unwrap();
+    let mut data = Vec::new();
+    for _ in 0..subject_count {
+        let mut conditions = Vec::new();
+        for _ in 0..condition_count {
+            conditions.push(read_f64_array(&mut file));
         }
-        b.push(sb);
+        data.push(conditions);
     }
+    data
+}
 
+// two-group file contains subject count and data arrays for group a,
+// followed by subject count and data arrays for group b
+pub fn read_two_group_data_file(filename: String) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+    let mut file = File::open(&filename).expect(&format!("failed to open input file {}", &filename));
+    let a = read_group(&mut file);
+    let b = read_group(&mut file);
     (a, b)
 }
+
+fn read_group(file: &mut File) -> Vec<Vec<f64>> {
+    let subject_count = file.read_u32::<LittleEndian>().unwrap();
+    let mut group = Vec::new();
+    for _ in 0..subject_count {
+        group.push(read_f64_array(file));
+    }
+    group
+}
+
+fn read_f64_array(file: &mut File) -> Vec<f64> {
+    let len = file.read_u32::<LittleEndian>().unwrap();
+    let mut data = Vec::new();
+    for _ in 0..len {
+        data.push(file.read_f64::<LittleEndian>().unwrap());
+    }
+    data
+}
@@ -7,12 +7,26 @@ extern crate byteorder;
 use libtfce::field::generate_1d_field;
 use libtfce::tfce;
 use libtfce::permutation;
-use libtfce::read_data_file;
+use libtfce::permutation::{Tail, Permutations, SignFlips};
+use libtfce::enhancement::{Enhancement, Tfce, HeightGridTfce, AnisotropicTfce, ClusterMass, ClusterExtent, MaxT};
+use libtfce::tfce::approximate_tfce::Discretisation;
+use libtfce::tfce::anisotropic::DimensionExtent;
+use libtfce::tfce::height_grid::HeightGrid;
+use libtfce::exchangeability::{ExchangeabilityBlocks, read_exchangeability_blocks};
+use libtfce::ttest::TTest;
+use libtfce::robust::{PairedStatistic, DEFAULT_TRIM};
+use libtfce::glm;
+use libtfce::glm::{Glm, Contrast};
+use libtfce::anova::{RmAnova, Effect};
+use libtfce::correlation::{Correlation, CovariateCorrelation};
+use libtfce::smoothing::{VarianceSmoothing, SmoothingKernel, SmoothedTTest};
+use libtfce::{read_data_file, read_single_condition_data_file, read_two_group_data_file, read_multi_condition_data_file, read_covariate_file, read_voxel_weight_file};
+use libtfce::voxel::{Voxel, Dimension};
 use libtfce::explore_tfce_permutation;
 use libtfce::freesurfer;
 use libtfce::stc;
 
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches};
 use std::fs::File;
 use byteorder::{LittleEndian, WriteBytesExt};
 
@@ -23,12 +37,82 @@ fn main() {
              .display_order(1)
              .help("TFCE graph configuration"))
 
-        .arg(Arg::with_name("permutation-count").long("permutation-count").short("n").value_name("N").required(true).takes_value(true)
+        .arg(Arg::with_name("permutation-count").long("permutation-count").short("n").value_name("N").takes_value(true)
+             .required_unless("import-permutations")
              .help("Number of permutations"))
-        .arg(Arg::with_name("h").short("h").value_name("value").required(true).takes_value(true)
-             .help("TFCE parameter H, intensity weighting (2 is recommended)"))
-        .arg(Arg::with_name("e").short("e").value_name("value").required(true).takes_value(true)
-             .help("TFCE parameter E, cluster extent weighting (0.666 is recommended)"))
+        .arg(Arg::with_name("seed").long("seed").value_name("values").takes_value(true)
+             .use_delimiter(true)
+             .default_value("17556,31771,29830,29830")
+             .help("comma-separated list of integers used to seed the random number generator for permutations"))
+        .arg(Arg::with_name("export-permutations").long("export-permutations").value_name("filename").takes_value(true)
+             .help("write permutations to file, one permutation per line. For paired test, sign flip matrix with 1 = keep, -1 = flip, one column per subject. For independent, glm and correlation tests, 1-based indices of pooled subjects (independent tests: first ones being assigned to group A; glm: order of permuted residuals; correlation: order of subjects paired with covariate values). For rm-anova test, 1-based indices of all subject conditions, subject by subject; conditions may only be reordered within subject"))
+        .arg(Arg::with_name("import-permutations").long("import-permutations").value_name("filename").takes_value(true)
+             .conflicts_with_all(&["permutation-count", "seed"])
+             .help("read permutations from file (in --export-permutations format) instead of generating random permutations"))
+        .arg(Arg::with_name("exchangeability-blocks").long("exchangeability-blocks").value_name("filename").takes_value(true)
+             .help("file with exchangeability block label (any word or number) of every subject, separated by whitespace or commas, e.g. site or session. Permutations only exchange subjects as allowed by blocks. For one-way rm-anova test, labels are given for every subject condition, subject by subject, and conditions are only shuffled within their block"))
+        .arg(Arg::with_name("block-permutation").long("block-permutation").value_name("type").takes_value(true)
+             .possible_values(&["within", "whole"])
+             .default_value("within")
+             .help("how exchangeability blocks restrict permutations: shuffle subjects only within their block (within), or shuffle blocks of equal size as a whole keeping order of subjects and flip all subjects of a block together (whole). Sign flips are independent for every subject, so tests with sign flips require whole"))
+        .arg(Arg::with_name("test").long("test").value_name("test").takes_value(true)
+             .possible_values(&["paired", "one-sample", "student", "welch", "glm", "rm-anova", "pearson", "spearman"])
+             .default_value("paired")
+             .help("test to compute: paired t-test (conditions A and B of same subjects, sign flip permutations), one-sample t-test of single condition against --reference (sign flip permutations), independent two-sample t-test with equal (student) or unequal (welch) variances (groups A and B of different subjects, label shuffling permutations) general linear model given by --design and --contrast (single condition per subject, Freedman-Lane permutations), pearson or spearman correlation with --covariate converted to t values (single condition per subject, covariate shuffling permutations) or repeated-measures anova F test for conditions given by --factor-levels (several conditions per subject, condition labels shuffled within subjects; in two-way anova, main effects only shuffle levels of their own factor and interaction shuffles levels of both factors)"))
+        .arg(Arg::with_name("statistic").long("statistic").value_name("statistic").takes_value(true)
+             .possible_values(&["t", "wilcoxon", "yuen", "median"])
+             .default_value("t")
+             .help("statistic for paired test: t value, wilcoxon signed-rank z value, yuen trimmed-mean t value (trimmed by --trim) or median difference. Robust statistics reduce influence of outlier subjects"))
+        .arg(Arg::with_name("trim").long("trim").value_name("proportion").takes_value(true)
+             .help("proportion of subjects trimmed from each end for yuen statistic, default 0.2"))
+        .arg(Arg::with_name("variance-smoothing").long("variance-smoothing").value_name("kernel").takes_value(true)
+             .possible_values(&["neighbours", "gaussian"])
+             .help("smooth variance of paired t-test over voxel graph before computing t values: average over each voxel and its neighbours, or gaussian weights of distance in links with --smoothing-sigma. Helps against noisy variance estimates with few subjects"))
+        .arg(Arg::with_name("smoothing-sigma").long("smoothing-sigma").value_name("links").takes_value(true)
+             .default_value("1")
+             .help("sigma of gaussian variance smoothing, in number of links between voxels"))
+        .arg(Arg::with_name("design").long("design").value_name("filename").takes_value(true)
+             .help("design matrix for glm test: one row per subject, one column per regressor, whitespace-separated. FSL .mat files are accepted"))
+        .arg(Arg::with_name("contrast").long("contrast").value_name("filename").takes_value(true)
+             .help("contrast for glm test: single row for t contrast, several rows for F contrast. FSL .con/.fts files are accepted"))
+        .arg(Arg::with_name("covariate").long("covariate").value_name("filename").takes_value(true)
+             .help("covariate for pearson and spearman tests: one value per subject (e.g. behavioural score), whitespace-separated, in the same order as subjects in input data"))
+        .arg(Arg::with_name("factor-levels").long("factor-levels").value_name("values").takes_value(true)
+             .use_delimiter(true)
+             .help("level counts of within-subject factors for rm-anova test: single value for one-way anova, two comma-separated values for two-way anova. Conditions are ordered with levels of the first factor major, e.g. A1B1, A1B2, A2B1, A2B2"))
+        .arg(Arg::with_name("effect").long("effect").value_name("effect").takes_value(true)
+             .possible_values(&["a", "b", "interaction"])
+             .default_value("a")
+             .help("effect to test in two-way rm-anova: main effect of first factor, main effect of second factor or their interaction"))
+        .arg(Arg::with_name("reference").long("reference").value_name("value").takes_value(true)
+             .default_value("0")
+             .help("reference value for one-sample test, e.g. chance level"))
+        .arg(Arg::with_name("h").short("h").value_name("value").takes_value(true)
+             .help("TFCE parameter H, intensity weighting (2 is recommended), required for TFCE"))
+        .arg(Arg::with_name("e").short("e").value_name("value").takes_value(true)
+             .help("TFCE parameter E, cluster extent weighting (0.666 is recommended), required for TFCE"))
+        .arg(Arg::with_name("enhancement").long("enhancement").value_name("method").takes_value(true)
+             .possible_values(&["tfce", "cluster-mass", "cluster-extent", "max-t"])
+             .default_value("tfce")
+             .help("enhancement of statistic map before max statistic correction: TFCE, sum of statistic values of clusters above --cluster-threshold (cluster-mass), sum of voxel weights (voxel count by default) of clusters above --cluster-threshold (cluster-extent), or no enhancement (max-t)"))
+        .arg(Arg::with_name("tfce-integration").long("tfce-integration").value_name("method").takes_value(true)
+             .possible_values(&["exact", "scaled-steps", "unscaled-steps"])
+             .default_value("exact")
+             .help("TFCE integration: exact integral or sum over fixed height grid, either heights h0 + dh, h0 + 2 dh, ... up to max inclusive in single precision, clusters >= height, scaled by dh (scaled-steps), or heights h0, h0 + dh, ... below max, clusters > height, not scaled (unscaled-steps)"))
+        .arg(Arg::with_name("tfce-h0").long("tfce-h0").value_name("value").takes_value(true)
+             .default_value("0")
+             .help("starting height for scaled-steps and unscaled-steps TFCE integration"))
+        .arg(Arg::with_name("tfce-dh").long("tfce-dh").value_name("value").takes_value(true)
+             .help("height step for scaled-steps and unscaled-steps TFCE integration, scaled-steps default is max value / 100, required for unscaled-steps"))
+        .arg(Arg::with_name("e-time").long("e-time").value_name("value").takes_value(true)
+             .help("separate TFCE extent exponent for time (type=mesh-time): extent term becomes spatial extent^E * (time scale * temporal extent)^e-time, integrated in --tfce-dh steps (default 100 steps)"))
+        .arg(Arg::with_name("time-scale").long("time-scale").value_name("value").takes_value(true)
+             .default_value("1")
+             .help("scale of temporal extent relative to spatial extent, e.g. vertices per time point, used with --e-time"))
+        .arg(Arg::with_name("voxel-weights").long("voxel-weights").value_name("filename").takes_value(true)
+             .help("file with non-negative weight of every voxel (type=1d) or source space vertex (type=mesh-time), separated by whitespace, e.g. vertex area. Cluster extent is sum of weights instead of voxel count"))
+        .arg(Arg::with_name("cluster-threshold").long("cluster-threshold").value_name("value").takes_value(true)
+             .help("cluster forming threshold of statistic for cluster-mass and cluster-extent enhancement, applied to absolute value for negative tail"))
 
         .arg(Arg::with_name("explore").long("explore").takes_value(false)
              .help("run in exploration mode: try all H values from --h to --h-max (increment by --h-step), try all E values from --e to --e-max (increment by --e-step)"))
@@ -40,68 +124,297 @@ fn main() {
              .help("max value for E in --explore mode"))
         .arg(Arg::with_name("e-step").long("e-step").value_name("value").takes_value(true)
              .help("increment for E in --explore mode"))
+        .arg(Arg::with_name("tail").long("tail").value_name("tail").takes_value(true)
+             .possible_values(&["two-sided", "positive", "negative"])
+             .default_value("two-sided")
+             .help("which effects to test for: two-sided test, or one-sided test that discards negative (positive) or positive (negative) t-values"))
         .arg(Arg::with_name("negative").long("negative").takes_value(false)
-             .help("perform one-sided test - assume that our effect is negative and ignore all positive effects. Discards positive t-values"))
+             .conflicts_with_all(&["positive", "tail"])
+             .help("shorthand for --tail negative"))
         .arg(Arg::with_name("positive").long("positive").takes_value(false)
-             .help("perform one-sided test - assume that our effect is positive and ignore all negative effects. Discards negative t-values"))
+             .conflicts_with_all(&["negative", "tail"])
+             .help("shorthand for --tail positive"))
+        .arg(Arg::with_name("alpha").long("alpha").value_name("value").takes_value(true)
+             .default_value("0.05")
+             .help("significance level for FWER-corrected significance mask"))
+
+        .arg(Arg::with_name("output-p-values").long("output-p-values").takes_value(false)
+             .help("write FWER-corrected p-value for every voxel instead of significance mask. For type=1d, output file contains value count followed by values as f64"))
+        .arg(Arg::with_name("signed-output").long("signed-output").takes_value(false)
+             .help("sign significance mask and p-values with the direction of the effect: mask is 1 for positive, -1 for negative effects and 0 where not significant (i8 instead of u8 for type=1d), p-values of negative effects are negated"))
+        .arg(Arg::with_name("output-standard-errors").long("output-standard-errors").value_name("filename").takes_value(true)
+             .help("file to write Monte Carlo standard errors of FWER-corrected p-values to (type=1d), in the same format as p-values: value count followed by values as f64"))
+        .arg(Arg::with_name("output-standard-error-stcs").long("output-standard-error-stcs").value_name("lh.stc rh.stc").takes_value(true).number_of_values(2)
+             .help("lh and rh stc files to write Monte Carlo standard errors of FWER-corrected p-values to (type=mesh-time)"))
 
         .arg(Arg::with_name("input-file").long("input-file").value_name("filename").takes_value(true)
-             .help("Input file for (type=1d)"))
+             .help("Input file for (type=1d). For one-sample, glm and correlation tests, contains single condition per subject. For rm-anova test, contains subject count, condition count and all conditions of every subject. For independent tests, contains group A subjects followed by group B subjects"))
         .arg(Arg::with_name("output-file").long("output-file").value_name("filename").takes_value(true)
              .help("Output file (type=1d)"))
 
         .arg(Arg::with_name("source-space").long("source-space").value_name("filename").takes_value(true)
              .help("Freesurfer source space .fif file, used to extract mesh data (type=mesh-time)"))
         .arg(Arg::with_name("input-stcs").long("input-stcs").value_name("filenames...").takes_value(true).multiple(true)
-             .help("Input stc files, 4 per subject. Files must be in order: subj1-condA-lh.stc, subj1-condA-rh.stc, subj1-condB-lh.stc, subj1-condB-rh.stc, subj2-condA-lh.stc, etc. (type=mesh-time)"))
+             .help("Input stc files, 4 per subject. Files must be in order: subj1-condA-lh.stc, subj1-condA-rh.stc, subj1-condB-lh.stc, subj1-condB-rh.stc, subj2-condA-lh.stc, etc. For one-sample, glm, correlation and independent tests (group A), 2 per subject: subj1-lh.stc, subj1-rh.stc, subj2-lh.stc, etc. For rm-anova test, 2 per condition per subject: subj1-cond1-lh.stc, subj1-cond1-rh.stc, subj1-cond2-lh.stc, etc. (type=mesh-time)"))
+        .arg(Arg::with_name("input-stcs-b").long("input-stcs-b").value_name("filenames...").takes_value(true).multiple(true)
+             .help("Group B stc files for independent tests, 2 per subject: subj1-lh.stc, subj1-rh.stc, subj2-lh.stc, etc. (type=mesh-time)"))
         .arg(Arg::with_name("output-stcs").long("output-stcs").value_name("lh.stc rh.stc").takes_value(true).number_of_values(2)
              .help("Output stc files, lh and rh stc filenames (type=mesh-time)"))
 
         .get_matches();
 
-    let permutation_count =
-        args.value_of("permutation-count").unwrap().parse::<i32>()
-        .expect("failed to parse permutation-cost");
-    let h =
-        args.value_of("h").unwrap().parse::<f64>()
-        .expect("failed to parse h");
-    let e =
-        args.value_of("e").unwrap().parse::<f64>()
-        .expect("failed to parse e");
+    let enhancement_method = args.value_of("enhancement").unwrap();
+    let (e, h) =
+        if enhancement_method == "tfce" {
+            (
+                args.value_of("e").expect("-e is required for TFCE").parse::<f64>()
+                    .expect("failed to parse e"),
+                args.value_of("h").expect("-h is required for TFCE").parse::<f64>()
+                    .expect("failed to parse h")
+            )
+        } else {
+            (0.0, 0.0)
+        };
+    let enhancement: Box<Enhancement> =
+        match enhancement_method {
+            "tfce" => {
+                let h0 =
+                    args.value_of("tfce-h0").unwrap().parse::<f64>()
+                    .expect("failed to parse tfce-h0");
+                let dh =
+                    args.value_of("tfce-dh").map(|v| v.parse::<f64>().expect("failed to parse tfce-dh"));
+                if h0 < 0.0 {
+                    panic!("--tfce-h0 must not be negative");
+                }
+                if dh.map_or(false, |dh| !(dh > 0.0)) {
+                    panic!("--tfce-dh must be positive");
+                }
+                match (args.value_of("e-time"), args.value_of("tfce-integration")) {
+                    (Some(e_time), Some("exact")) => {
+                        let e_time = e_time.parse::<f64>().expect("failed to parse e-time");
+                        let time_scale =
+                            args.value_of("time-scale").unwrap().parse::<f64>()
+                            .expect("failed to parse time-scale");
+                        if args.value_of("type") != Some("mesh-time") {
+                            panic!("--e-time is only supported for type=mesh-time");
+                        }
+                        if !(time_scale > 0.0) {
+                            panic!("--time-scale must be positive");
+                        }
+                        Box::new(AnisotropicTfce {
+                            extents: vec![
+                                DimensionExtent { dimension: Dimension::Space, e, scale: 1.0 },
+                                DimensionExtent { dimension: Dimension::Time, e: e_time, scale: time_scale }
+                            ],
+                            h, h0,
+                            discretisation: dh.map_or(Discretisation::Steps(100), Discretisation::Dh)
+                        })
+                    },
+                    (Some(_), _) => panic!("--e-time can not be combined with --tfce-integration"),
+                    (None, Some("scaled-steps")) => Box::new(HeightGridTfce { e, h, grid: HeightGrid::ScaledSteps { h0, dh } }),
+                    (None, Some("unscaled-steps")) => Box::new(HeightGridTfce {
+                        e, h,
+                        grid: HeightGrid::UnscaledSteps { start: h0, step: dh.expect("--tfce-dh is required for unscaled-steps integration") }
+                    }),
+                    _ => Box::new(Tfce { e, h })
+                }
+            },
+            "max-t" => Box::new(MaxT),
+            method => {
+                let threshold =
+                    args.value_of("cluster-threshold")
+                    .expect("--cluster-threshold is required for cluster enhancement")
+                    .parse::<f64>().expect("failed to parse cluster-threshold");
+                if !(threshold > 0.0) {
+                    panic!("--cluster-threshold must be positive");
+                }
+                if method == "cluster-mass" {
+                    Box::new(ClusterMass { threshold })
+                } else {
+                    Box::new(ClusterExtent { threshold })
+                }
+            }
+        };
 
     let explore = args.is_present("explore");
+    let output_p_values = args.is_present("output-p-values");
+    let signed_output = args.is_present("signed-output");
+
+    let tail =
+        if args.is_present("negative") {
+            Tail::Negative
+        } else if args.is_present("positive") {
+            Tail::Positive
+        } else {
+            match args.value_of("tail") {
+                Some("positive") => Tail::Positive,
+                Some("negative") => Tail::Negative,
+                _ => Tail::TwoSided
+            }
+        };
+    let design =
+        match args.value_of("test") {
+            Some("one-sample") =>
+                Design::OneSample(
+                    args.value_of("reference").unwrap().parse::<f64>()
+                    .expect("failed to parse reference")
+                ),
+            Some("student") => Design::Independent(TTest::Student),
+            Some("welch") => Design::Independent(TTest::Welch),
+            Some("glm") => {
+                let design_matrix = glm::read_matrix(args.value_of("design").expect("--design is required for glm test"));
+                let mut contrast_rows = glm::read_matrix(args.value_of("contrast").expect("--contrast is required for glm test"));
+                let contrast =
+                    if contrast_rows.len() == 1 {
+                        Contrast::T(contrast_rows.remove(0))
+                    } else {
+                        Contrast::F(contrast_rows)
+                    };
+                Design::Glm(Glm::new(design_matrix, contrast))
+            },
+            Some("pearson") | Some("spearman") => {
+                let covariate = read_covariate_file(args.value_of("covariate").expect("--covariate is required for correlation tests"));
+                if args.value_of("test") == Some("spearman") {
+                    Design::Correlation(Correlation::Spearman, covariate)
+                } else {
+                    Design::Correlation(Correlation::Pearson, covariate)
+                }
+            },
+            Some("rm-anova") => {
+                let levels =
+                    args.values_of("factor-levels").expect("--factor-levels is required for rm-anova test")
+                    .map(|v| v.parse::<usize>().expect("failed to parse factor-levels"))
+                    .collect::<Vec<usize>>();
+                if levels.iter().any(|&l| l < 2) {
+                    panic!("every factor in --factor-levels must have at least 2 levels");
+                }
+                let effect =
+                    match args.value_of("effect") {
+                        Some("b") => Effect::MainB,
+                        Some("interaction") => Effect::Interaction,
+                        _ => Effect::MainA
+                    };
+                match levels.len() {
+                    1 if effect == Effect::MainA => Design::RmAnova(RmAnova::one_way(levels[0])),
+                    1 => panic!("--effect b and interaction require two factors in --factor-levels"),
+                    2 => Design::RmAnova(RmAnova::two_way(levels[0], levels[1], effect)),
+                    _ => panic!("--factor-levels must contain one or two values")
+                }
+            },
+            _ => {
+                let trim =
+                    args.value_of("trim").map_or(DEFAULT_TRIM, |v| v.parse::<f64>().expect("failed to parse trim"));
+                if !(trim >= 0.0 && trim < 0.5) {
+                    panic!("--trim must be between 0 and 0.5");
+                }
+                let sigma =
+                    args.value_of("smoothing-sigma").unwrap().parse::<f64>()
+                    .expect("failed to parse smoothing-sigma");
+                if !(sigma > 0.0) {
+                    panic!("--smoothing-sigma must be positive");
+                }
+                match (args.value_of("statistic"), args.value_of("variance-smoothing")) {
+                    (Some("t"), Some("neighbours")) => Design::SmoothedPaired(VarianceSmoothing::Neighbours),
+                    (Some("t"), Some("gaussian")) => Design::SmoothedPaired(VarianceSmoothing::Gaussian(sigma)),
+                    (_, Some(_)) => panic!("--variance-smoothing is only supported for t statistic"),
+                    (Some("wilcoxon"), _) => Design::RobustPaired(PairedStatistic::Wilcoxon),
+                    (Some("yuen"), _) => Design::RobustPaired(PairedStatistic::Yuen(trim)),
+                    (Some("median"), _) => Design::RobustPaired(PairedStatistic::MedianDifference),
+                    _ => Design::Paired(TTest::Paired)
+                }
+            }
+        };
+    if args.occurrences_of("statistic") > 0 && !design.is_paired() {
+        panic!("--statistic is only supported for paired test");
+    }
+    if args.is_present("variance-smoothing") && !design.is_paired() {
+        panic!("--variance-smoothing is only supported for paired test");
+    }
+    if explore && (enhancement_method != "tfce" || args.value_of("tfce-integration") != Some("exact") || args.is_present("e-time")) {
+        panic!("--explore is only supported for exact TFCE enhancement");
+    }
+    if explore && design.ttest().is_none() {
+        panic!("--explore is only supported for paired and independent tests without variance smoothing");
+    }
+    if let Design::RmAnova(_) = design {
+        if tail == Tail::Negative {
+            panic!("rm-anova test does not support negative tail, F values are non-negative");
+        }
+    }
+
+    if args.is_present("output-standard-errors") && args.value_of("type") != Some("1d") {
+        panic!("--output-standard-errors is only supported for type=1d, use --output-standard-error-stcs for type=mesh-time");
+    }
+    if args.is_present("output-standard-error-stcs") && args.value_of("type") != Some("mesh-time") {
+        panic!("--output-standard-error-stcs is only supported for type=mesh-time");
+    }
 
-    let negative = args.is_present("negative");
-    let positive = args.is_present("positive");
-    if negative && positive {
-        panic!("can't peform both negative and positive one-sided test simultaneously. Only one of --negative and --positive can be provided");
+    let alpha =
+        args.value_of("alpha").unwrap().parse::<f64>()
+        .expect("failed to parse alpha");
+    if !(alpha > 0.0 && alpha < 1.0) {
+        panic!("--alpha must be between 0 and 1");
     }
 
     match args.value_of("type") {
         Some("1d") => {
             let data_file = args.value_of("input-file").expect("--input-file not provided").into();
 
-            let (a, b) = read_data_file(data_file);
+            let (a, b) =
+                match design {
+                    Design::Paired(_) | Design::RobustPaired(_) | Design::SmoothedPaired(_) => read_data_file(data_file),
+                    Design::Independent(_) => read_two_group_data_file(data_file),
+                    Design::OneSample(_) | Design::Glm(_) | Design::Correlation(_, _) => (read_single_condition_data_file(data_file), Vec::new()),
+                    Design::RmAnova(anova) => {
+                        let data = read_multi_condition_data_file(data_file);
+                        if data[0].len() != anova.condition_count() {
+                            panic!("input file has {} conditions per subject, but --factor-levels require {}", data[0].len(), anova.condition_count());
+                        }
+                        (data.into_iter().flat_map(|conditions| conditions.into_iter()).collect(), Vec::new())
+                    }
+                };
             let mut voxels = generate_1d_field(a[0].len());
+            set_voxel_weights(&args, &mut voxels);
+            let permutations = get_permutations(&args, &design, a.len(), b.len());
 
             if !explore {
-                let result = tfce::run_permutation(
+                let result = run_tfce_permutation(
                     &mut voxels,
                     &a, &b,
-                    permutation_count,
-                    e, h,
-                    negative, positive
+                    &design, &permutations,
+                    &*enhancement,
+                    tail, alpha
                 );
 
-                eprintln!("Statistically significant periods: {:?}", permutation::get_periods(permutation::significant_indices(&result)));
+                eprintln!("Statistically significant periods: {:?}", permutation::get_periods(permutation::significant_indices(&result.significant)));
+                report_min_p_value(&result);
 
                 let mut output_file =
                     File::create(args.value_of("output-file").expect("--output-file not provided"))
                         .expect("failed to create output file");
 
-                output_file.write_u32::<LittleEndian>(result.len() as u32).unwrap();
-                for b in result.into_iter() {
-                    output_file.write_u8(if b { 1 } else { 0 }).unwrap();
+                if let Some(filename) = args.value_of("output-standard-errors") {
+                    let mut se_file = File::create(filename).expect("failed to create standard error file");
+                    se_file.write_u32::<LittleEndian>(result.p_value_standard_errors.len() as u32).unwrap();
+                    for &se in result.p_value_standard_errors.iter() {
+                        se_file.write_f64::<LittleEndian>(se).unwrap();
+                    }
+                }
+
+                let output_values = output_map(result, output_p_values, signed_output);
+                output_file.write_u32::<LittleEndian>(output_values.len() as u32).unwrap();
+                if output_p_values {
+                    for p in output_values.into_iter() {
+                        output_file.write_f64::<LittleEndian>(p).unwrap();
+                    }
+                } else if signed_output {
+                    for s in output_values.into_iter() {
+                        output_file.write_i8(s as i8).unwrap();
+                    }
+                } else {
+                    for b in output_values.into_iter() {
+                        output_file.write_u8(b as u8).unwrap();
+                    }
                 }
             } else {
                 let h_max =
@@ -119,11 +432,11 @@ fn main() {
 
                 explore_tfce_permutation(
                     &a, &b,
-                    permutation_count,
+                    &design.ttest().unwrap(), &permutations,
                     &mut voxels,
                     e, e_step, e_max,
                     h, h_step, h_max,
-                    negative, positive
+                    tail, alpha
                 );
             }
         },
@@ -137,9 +450,31 @@ fn main() {
                 .expect("--input-stcs is required for type=mesh-time")
                 .collect::<Vec<&str>>();
 
-            if input_stc_filenames.len() % 4 != 0 {
+            let paired = design.is_paired();
+
+            if paired && input_stc_filenames.len() % 4 != 0 {
                 panic!("--input-stcs must contain 4 files per subject");
             }
+            if let Design::RmAnova(anova) = design {
+                if input_stc_filenames.len() % (2 * anova.condition_count()) != 0 {
+                    panic!("--input-stcs must contain {} files per subject, 2 per condition", 2 * anova.condition_count());
+                }
+            } else if !paired && input_stc_filenames.len() % 2 != 0 {
+                panic!("--input-stcs must contain 2 files per subject for one-sample, glm, correlation and independent tests");
+            }
+
+            let input_stc_b_filenames =
+                if let Design::Independent(_) = design {
+                    args.values_of("input-stcs-b")
+                        .expect("--input-stcs-b is required for independent tests with type=mesh-time")
+                        .collect::<Vec<&str>>()
+                } else {
+                    Vec::new()
+                };
+
+            if input_stc_b_filenames.len() % 2 != 0 {
+                panic!("--input-stcs-b must contain 2 files per subject");
+            }
 
             let output_stc_filenames =
                 args.values_of("output-stcs")
@@ -156,56 +491,58 @@ fn main() {
             let mut a = Vec::new();
             let mut b = Vec::new();
 
-            for subj_stcs in input_stc_filenames.exact_chunks(4) {
-                let (a_lh, a_rh) = (stc::read(subj_stcs[0]), stc::read(subj_stcs[1]));
-                let (b_lh, b_rh) = (stc::read(subj_stcs[2]), stc::read(subj_stcs[3]));
+            if paired {
+                for subj_stcs in input_stc_filenames.exact_chunks(4) {
+                    let (a_lh, a_rh) = (stc::read(subj_stcs[0]), stc::read(subj_stcs[1]));
+                    let (b_lh, b_rh) = (stc::read(subj_stcs[2]), stc::read(subj_stcs[3]));
 
-                a.push(stc::concat_pair(&a_lh, &a_rh));
-                b.push(stc::concat_pair(&b_lh, &b_rh));
+                    a.push(stc::concat_pair(&a_lh, &a_rh));
+                    b.push(stc::concat_pair(&b_lh, &b_rh));
 
-                stcs_a.push((a_lh, a_rh));
-                stcs_b.push((b_lh, b_rh));
+                    stcs_a.push((a_lh, a_rh));
+                    stcs_b.push((b_lh, b_rh));
+                }
+            } else {
+                for subj_stcs in input_stc_filenames.chunks_exact(2) {
+                    let (a_lh, a_rh) = (stc::read(subj_stcs[0]), stc::read(subj_stcs[1]));
+                    a.push(stc::concat_pair(&a_lh, &a_rh));
+                    stcs_a.push((a_lh, a_rh));
+                }
+                for subj_stcs in input_stc_b_filenames.chunks_exact(2) {
+                    let (b_lh, b_rh) = (stc::read(subj_stcs[0]), stc::read(subj_stcs[1]));
+                    b.push(stc::concat_pair(&b_lh, &b_rh));
+                    stcs_b.push((b_lh, b_rh));
+                }
             }
 
+            let mut source_space = freesurfer::read_source_space_to_graph(source_space_filename);
+            // weights of vertices are copied to every time point
+            set_voxel_weights(&args, &mut source_space);
             let mut voxels =
                 freesurfer::extend_graph_into_time(
-                    freesurfer::read_source_space_to_graph(source_space_filename),
+                    source_space,
                     stcs_a[0].0.time_count
                 );
+            let permutations = get_permutations(&args, &design, a.len(), b.len());
 
             if !explore {
-                let result = tfce::run_permutation(
+                let result = run_tfce_permutation(
                     &mut voxels,
                     &a, &b,
-                    permutation_count,
-                    e, h,
-                    negative, positive
+                    &design, &permutations,
+                    &*enhancement,
+                    tail, alpha
                 );
 
-                eprintln!("Statistically significant periods: {:?}", permutation::get_periods(permutation::significant_indices(&result)).len());
-
-                let mut output_stc_lh = stc::read(input_stc_filenames[0]);
-                let mut output_stc_rh = stc::read(input_stc_filenames[1]);
-                let mut output_data_lh = Vec::new();
-                let mut output_data_rh = Vec::new();
-                for t in 0..output_stc_lh.time_count {
-                    let mut time_slice_lh = Vec::new();
-                    for i in 0..output_stc_lh.vertex_count {
-                        time_slice_lh.push(if result[(output_stc_lh.vertex_count + output_stc_rh.vertex_count) * t + i] { 1.0 } else { 0.0 });
-                    }
-                    output_data_lh.push(time_slice_lh);
+                eprintln!("Statistically significant periods: {:?}", permutation::get_periods(permutation::significant_indices(&result.significant)).len());
+                report_min_p_value(&result);
 
-                    let mut time_slice_rh = Vec::new();
-                    for i in 0..output_stc_rh.vertex_count {
-                        time_slice_rh.push(if result[(output_stc_lh.vertex_count + output_stc_rh.vertex_count) * t + output_stc_lh.vertex_count + i] { 1.0 } else { 0.0 });
-                    }
-                    output_data_rh.push(time_slice_rh);
+                if let Some(filenames) = args.values_of("output-standard-error-stcs") {
+                    write_stc_pair(&filenames.collect(), &input_stc_filenames, &result.p_value_standard_errors);
                 }
 
-                output_stc_lh.data = output_data_lh;
-                output_stc_rh.data = output_data_rh;
-                stc::write(output_stc_filenames[0], output_stc_lh);
-                stc::write(output_stc_filenames[1], output_stc_rh);
+                let output_values = output_map(result, output_p_values, signed_output);
+                write_stc_pair(&output_stc_filenames, &input_stc_filenames, &output_values);
 
             } else {
                 let h_max =
@@ -223,14 +560,280 @@ fn main() {
 
                 explore_tfce_permutation(
                     &a, &b,
-                    permutation_count,
+                    &design.ttest().unwrap(), &permutations,
                     &mut voxels,
                     e, e_step, e_max,
                     h, h_step, h_max,
-                    negative, positive
+                    tail, alpha
                 );
             }
         },
         _ => panic!("unknown operation type: {}", args.value_of("type").unwrap())
     };
 }
+
+enum Design {
+    Paired(TTest),
+    RobustPaired(PairedStatistic),
+    SmoothedPaired(VarianceSmoothing),
+    OneSample(f64),
+    Independent(TTest),
+    Glm(Glm),
+    Correlation(Correlation, Vec<f64>),
+    RmAnova(RmAnova)
+}
+
+impl Design {
+    // two conditions per subject
+    fn is_paired(&self) -> bool {
+        match *self {
+            Design::Paired(_) | Design::RobustPaired(_) | Design::SmoothedPaired(_) => true,
+            _ => false
+        }
+    }
+
+    fn uses_sign_flips(&self) -> bool {
+        match *self {
+            Design::Paired(_) | Design::RobustPaired(_) | Design::SmoothedPaired(_) | Design::OneSample(_) => true,
+            _ => false
+        }
+    }
+
+    fn ttest(&self) -> Option<TTest> {
+        match *self {
+            Design::Paired(test) => Some(test),
+            Design::Independent(test) => Some(test),
+            _ => None
+        }
+    }
+}
+
+fn run_tfce_permutation(
+    voxels: &mut Vec<Voxel>,
+    a: &Vec<Vec<f64>>,
+    b: &Vec<Vec<f64>>,
+    design: &Design,
+    permutations: &Permutations,
+    enhancement: &Enhancement,
+    tail: Tail,
+    alpha: f64
+) -> permutation::PermutationResult {
+    match (design, permutations) {
+        (&Design::OneSample(reference), &Permutations::SignFlips(ref sign_flips)) =>
+            tfce::run_one_sample_permutation(voxels, a, reference, sign_flips, enhancement, tail, alpha),
+        (&Design::SmoothedPaired(smoothing), _) => {
+            // kernel is built once from voxel graph and applied in every permutation
+            let statistic = SmoothedTTest { kernel: SmoothingKernel::new(voxels, smoothing) };
+            tfce::run_permutation(voxels, a, b, &statistic, permutations, enhancement, tail, alpha)
+        },
+        (&Design::OneSample(_), _) =>
+            panic!("one-sample test requires sign flip permutations"),
+        (&Design::Glm(ref glm), _) =>
+            tfce::run_glm_permutation(voxels, glm, a, permutations, enhancement, tail, alpha),
+        (&Design::Correlation(correlation, ref covariate), &Permutations::LabelShuffles(_)) => {
+            let statistic = CovariateCorrelation::new(correlation, covariate.clone());
+            tfce::run_permutation(voxels, a, b, &statistic, permutations, enhancement, tail, alpha)
+        },
+        (&Design::Correlation(_, _), _) =>
+            panic!("correlation test requires subject shuffles"),
+        (&Design::RmAnova(anova), &Permutations::LabelShuffles(ref shuffles)) => {
+            // a contains all conditions of every subject, subject by subject
+            let data = a.chunks(anova.condition_count()).map(|conditions| conditions.to_vec()).collect();
+            tfce::run_rm_anova_permutation(voxels, &data, anova, shuffles, enhancement, alpha)
+        },
+        (&Design::RmAnova(_), _) =>
+            panic!("rm-anova test requires within-subject shuffles"),
+        (&Design::RobustPaired(statistic), _) =>
+            tfce::run_permutation(voxels, a, b, &statistic, permutations, enhancement, tail, alpha),
+        (&Design::Paired(_), _) | (&Design::Independent(_), _) =>
+            tfce::run_permutation(voxels, a, b, &design.ttest().unwrap(), permutations, enhancement, tail, alpha)
+    }
+}
+
+fn get_permutations(args: &ArgMatches, design: &Design, na: usize, nb: usize) -> Permutations {
+    if let Design::Glm(ref glm) = *design {
+        if glm.observation_count() != na {
+            panic!("design matrix has {} rows, but there are {} subjects", glm.observation_count(), na);
+        }
+    }
+    if let Design::Correlation(_, ref covariate) = *design {
+        if covariate.len() != na {
+            panic!("covariate has {} values, but there are {} subjects", covariate.len(), na);
+        }
+    }
+
+    let blocks = get_exchangeability_blocks(args, design, na, nb);
+
+    let permutations =
+        if let Some(filename) = args.value_of("import-permutations") {
+            if design.uses_sign_flips() {
+                let sign_flips = permutation::read_sign_flips(filename);
+                if sign_flips.iter().any(|flips| flips.len() != na) {
+                    panic!("sign flip matrix in {} must have {} columns, one per subject", filename, na);
+                }
+                if let Some(ref blocks) = blocks {
+                    if !sign_flips.iter().all(|flips| blocks.allows_sign_flips(flips)) {
+                        panic!("sign flips in {} are not allowed by exchangeability blocks", filename);
+                    }
+                }
+                Permutations::SignFlips(SignFlips::sampled(sign_flips))
+            } else {
+                let shuffles = permutation::read_label_shuffles(filename);
+                if shuffles.iter().any(|order| order.len() != na + nb) {
+                    panic!("label shuffles in {} must have {} columns, one per subject", filename, na + nb);
+                }
+                if let Some(ref blocks) = blocks {
+                    if !shuffles.iter().all(|order| blocks.allows_shuffle(order)) {
+                        panic!("label shuffles in {} are not allowed by exchangeability blocks", filename);
+                    }
+                }
+                Permutations::LabelShuffles(shuffles)
+            }
+        } else {
+            let permutation_count =
+                args.value_of("permutation-count").unwrap().parse::<i32>()
+                .expect("failed to parse permutation-count");
+            let seed =
+                args.values_of("seed").unwrap()
+                .map(|v| v.parse::<usize>().expect("failed to parse seed"))
+                .collect::<Vec<usize>>();
+            if let Some(ref blocks) = blocks {
+                if design.uses_sign_flips() {
+                    Permutations::SignFlips(blocks.generate_sign_flips(permutation_count, &seed))
+                } else {
+                    Permutations::LabelShuffles(blocks.generate_shuffles(permutation_count, &seed))
+                }
+            } else if design.uses_sign_flips() {
+                Permutations::SignFlips(permutation::generate_sign_flips(na, permutation_count, &seed))
+            } else if let Design::RmAnova(anova) = *design {
+                Permutations::LabelShuffles(permutation::generate_rm_anova_shuffles(&anova, na / anova.condition_count(), permutation_count, &seed))
+            } else {
+                Permutations::LabelShuffles(permutation::generate_label_shuffles(na + nb, permutation_count, &seed))
+            }
+        };
+
+    if let Some(filename) = args.value_of("export-permutations") {
+        match permutations {
+            Permutations::SignFlips(ref sign_flips) => permutation::write_sign_flips(filename, sign_flips),
+            Permutations::LabelShuffles(ref shuffles) => permutation::write_label_shuffles(filename, shuffles)
+        }
+    }
+
+    permutations
+}
+
+// blocks are returned for units that are permuted: subjects for sign flips,
+// pooled subjects for label shuffles and subject conditions for rm-anova
+fn get_exchangeability_blocks(args: &ArgMatches, design: &Design, na: usize, nb: usize) -> Option<ExchangeabilityBlocks> {
+    let filename =
+        match args.value_of("exchangeability-blocks") {
+            Some(filename) => filename,
+            None => return None
+        };
+    let labels = read_exchangeability_blocks(filename);
+    let whole = args.value_of("block-permutation") == Some("whole");
+
+    if let Design::RmAnova(anova) = *design {
+        if whole {
+            panic!("whole-block permutation is not supported for rm-anova test");
+        }
+        if anova.levels_b > 1 {
+            panic!("exchangeability blocks are not supported for two-way rm-anova test, levels of its factors are shuffled separately");
+        }
+        // conditions are shuffled within subjects, so blocks of subjects would not
+        // restrict anything; blocks of conditions restrict shuffles further
+        let k = anova.condition_count();
+        let nsubj = na / k;
+        if labels.len() == nsubj {
+            panic!(
+                "rm-anova test shuffles conditions within subjects, so per-subject labels in {} have no effect; give {} labels, one per subject condition",
+                filename, na
+            );
+        }
+        if labels.len() != na {
+            panic!("{} must contain {} labels, one per subject condition", filename, na);
+        }
+        let nblocks = labels.iter().max().map_or(0, |&m| m + 1);
+        let observation_labels = (0..na).map(|i| (i / k) * nblocks + labels[i]).collect();
+        return Some(ExchangeabilityBlocks::within(observation_labels));
+    }
+
+    if design.uses_sign_flips() {
+        if !whole {
+            panic!("within-block exchangeability does not restrict sign flips, every subject is flipped independently; use --block-permutation whole");
+        }
+        if nb > 0 && labels.len() == na + nb {
+            panic!("{} contains a label for every condition, but paired test flips both conditions of a subject together; give {} labels, one per subject", filename, na);
+        }
+    }
+    let units = if design.uses_sign_flips() { na } else { na + nb };
+    if labels.len() != units {
+        panic!("{} must contain {} labels, one per subject", filename, units);
+    }
+    Some(if whole { ExchangeabilityBlocks::whole(labels) } else { ExchangeabilityBlocks::within(labels) })
+}
+
+fn set_voxel_weights(args: &ArgMatches, voxels: &mut Vec<Voxel>) {
+    if let Some(filename) = args.value_of("voxel-weights") {
+        let weights = read_voxel_weight_file(filename);
+        if weights.len() != voxels.len() {
+            panic!("{} must contain {} weights, one per voxel", filename, voxels.len());
+        }
+        if weights.iter().any(|&w| !(w >= 0.0)) {
+            panic!("voxel weights in {} must be non-negative", filename);
+        }
+        for (v, w) in voxels.iter_mut().zip(weights.into_iter()) {
+            v.weight = w;
+        }
+    }
+}
+
+// values are split into lh and rh time slices like the first subject's input stcs,
+// whose headers are reused
+fn write_stc_pair(filenames: &Vec<&str>, input_stc_filenames: &Vec<&str>, values: &Vec<f64>) {
+    let mut output_stc_lh = stc::read(input_stc_filenames[0]);
+    let mut output_stc_rh = stc::read(input_stc_filenames[1]);
+    let mut output_data_lh = Vec::new();
+    let mut output_data_rh = Vec::new();
+    for t in 0..output_stc_lh.time_count {
+        let mut time_slice_lh = Vec::new();
+        for i in 0..output_stc_lh.vertex_count {
+            time_slice_lh.push(values[(output_stc_lh.vertex_count + output_stc_rh.vertex_count) * t + i]);
+        }
+        output_data_lh.push(time_slice_lh);
+
+        let mut time_slice_rh = Vec::new();
+        for i in 0..output_stc_rh.vertex_count {
+            time_slice_rh.push(values[(output_stc_lh.vertex_count + output_stc_rh.vertex_count) * t + output_stc_lh.vertex_count + i]);
+        }
+        output_data_rh.push(time_slice_rh);
+    }
+
+    output_stc_lh.data = output_data_lh;
+    output_stc_rh.data = output_data_rh;
+    stc::write(filenames[0], output_stc_lh);
+    stc::write(filenames[1], output_stc_rh);
+}
+
+fn report_min_p_value(result: &permutation::PermutationResult) {
+    let mut min_i = 0;
+    for i in 1..result.p_values.len() {
+        if result.p_values[i] < result.p_values[min_i] {
+            min_i = i;
+        }
+    }
+    eprintln!(
+        "Smallest p-value: {:.6} (standard error {:.6}, {} permutations including observed data)",
+        result.p_values[min_i], result.p_value_standard_errors[min_i], result.null_distribution.len()
+    );
+}
+
+// significance mask (1 or 0) or p-values, signed with the direction of the effect if asked
+fn output_map(result: permutation::PermutationResult, output_p_values: bool, signed: bool) -> Vec<f64> {
+    let sign = result.statistic.iter().map(|&v| if signed && v < 0.0 { -1.0 } else { 1.0 }).collect::<Vec<f64>>();
+    if output_p_values {
+        result.p_values.into_iter().zip(sign.into_iter()).map(|(p, s)| p * s).collect()
+    } else {
+        result.significant.into_iter().zip(sign.into_iter()).map(|(b, s)| if b { s } else { 0.0 }).collect()
+    }
+}
@@ -1,35 +1,682 @@
 use rand::{Rng, StdRng, SeedableRng};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::ops::Deref;
+use ::glm::Glm;
+use ::statistic::Statistic;
+use ::exchangeability::ExchangeabilityBlocks;
+use ::anova::{RmAnova, Effect};
 
-pub fn run_permutation(
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tail {
+    TwoSided,
+    Positive,
+    Negative
+}
+
+impl Tail {
+    pub fn includes_positive(&self) -> bool {
+        *self != Tail::Negative
+    }
+
+    pub fn includes_negative(&self) -> bool {
+        *self != Tail::Positive
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PermutationResult {
+    pub statistic: Vec<f64>,
+    pub null_distribution: Vec<f64>,
+    pub threshold: f64,
+    pub significant: Vec<bool>,
+    pub p_values: Vec<f64>,
+    pub p_value_standard_errors: Vec<f64>
+}
+
+#[derive(Debug, Clone)]
+pub enum Permutations {
+    SignFlips(SignFlips),
+    LabelShuffles(Vec<Vec<usize>>)
+}
+
+impl Permutations {
+    pub fn run<S: Statistic + ?Sized>(
+        &self,
+        a: &Vec<Vec<f64>>,
+        b: &Vec<Vec<f64>>,
+        alpha: f64,
+        statistic: &S,
+        enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+    ) -> PermutationResult {
+        match *self {
+            Permutations::SignFlips(ref sign_flips) => run_permutation(a, b, sign_flips, alpha, statistic, enhance),
+            Permutations::LabelShuffles(ref shuffles) => run_label_permutation(a, b, shuffles, alpha, statistic, enhance)
+        }
+    }
+
+    // one result for every map returned by enhance
+    pub fn run_batch<S: Statistic + ?Sized>(
+        &self,
+        a: &Vec<Vec<f64>>,
+        b: &Vec<Vec<f64>>,
+        alpha: f64,
+        statistic: &S,
+        enhance: &mut FnMut(Vec<f64>) -> Vec<Vec<f64>>
+    ) -> Vec<PermutationResult> {
+        let observations = a.iter().chain(b.iter()).cloned().collect();
+        match *self {
+            Permutations::SignFlips(ref sign_flips) => {
+                assert!(a.len() == b.len(), "paired data must have same number of subjects in both conditions");
+                run_scheme_batch(&observations, &SignFlip::new(a.len(), sign_flips.clone()), alpha, statistic, enhance)
+            },
+            Permutations::LabelShuffles(ref shuffles) => {
+                let scheme = LabelShuffle::new(vec![a.len(), b.len()], shuffles.clone());
+                run_scheme_batch(&observations, &scheme, alpha, statistic, enhance)
+            }
+        }
+    }
+}
+
+// sign flip matrix, one row of flips per permutation. Exhaustive if rows
+// enumerate all allowed flips, which only generator knows: random or imported
+// rows may happen to look like full enumeration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignFlips {
+    pub flips: Vec<Vec<bool>>,
+    pub exhaustive: bool
+}
+
+impl SignFlips {
+    // random or imported flips
+    pub fn sampled(flips: Vec<Vec<bool>>) -> SignFlips {
+        SignFlips { flips, exhaustive: false }
+    }
+}
+
+impl Deref for SignFlips {
+    type Target = Vec<Vec<bool>>;
+
+    fn deref(&self) -> &Vec<Vec<bool>> {
+        &self.flips
+    }
+}
+
+pub const DEFAULT_SEED: [usize; 4] = [17556, 31771, 29830, 29830];
+
+// permutation scheme rearranges pooled observations; permuted order is split into
+// consecutive groups of group_sizes() observations, which are passed to statistic
+pub trait PermutationScheme {
+    fn group_sizes(&self) -> Vec<usize>;
+
+    fn permutation_count(&self) -> usize;
+
+    // order of pooled observations in i-th permutation
+    fn order(&self, i: usize) -> Vec<usize>;
+
+    // true if permutations enumerate all rearrangements allowed by scheme,
+    // so p-values are exact
+    fn is_exhaustive(&self) -> bool {
+        false
+    }
+
+    fn orders(&self) -> Vec<Vec<usize>> {
+        (0..self.permutation_count()).map(|i| self.order(i)).collect()
+    }
+}
+
+// observations are conditions a of all subjects followed by conditions b,
+// flipping subject swaps its a and b observations
+#[derive(Debug, Clone)]
+pub struct SignFlip {
+    pub nsubj: usize,
+    pub sign_flips: SignFlips
+}
+
+impl SignFlip {
+    pub fn new(nsubj: usize, sign_flips: SignFlips) -> SignFlip {
+        for flips in sign_flips.iter() {
+            assert!(flips.len() == nsubj, "sign flip matrix row has {} entries, expected {}", flips.len(), nsubj);
+        }
+        SignFlip { nsubj, sign_flips }
+    }
+
+    pub fn generate(nsubj: usize, n: i32, seed: &[usize]) -> SignFlip {
+        SignFlip::new(nsubj, generate_sign_flips(nsubj, n, seed))
+    }
+}
+
+impl PermutationScheme for SignFlip {
+    fn group_sizes(&self) -> Vec<usize> {
+        vec![self.nsubj, self.nsubj]
+    }
+
+    fn permutation_count(&self) -> usize {
+        self.sign_flips.len()
+    }
+
+    fn order(&self, i: usize) -> Vec<usize> {
+        let n = self.nsubj;
+        let mut order = (0..(2 * n)).collect::<Vec<usize>>();
+        for (s, &flip) in self.sign_flips[i].iter().enumerate() {
+            if flip {
+                order.swap(s, n + s);
+            }
+        }
+        order
+    }
+
+    fn is_exhaustive(&self) -> bool {
+        self.sign_flips.exhaustive
+    }
+}
+
+// shuffles are permutations of all pooled observations
+#[derive(Debug, Clone)]
+pub struct LabelShuffle {
+    pub group_sizes: Vec<usize>,
+    pub shuffles: Vec<Vec<usize>>
+}
+
+impl LabelShuffle {
+    pub fn new(group_sizes: Vec<usize>, shuffles: Vec<Vec<usize>>) -> LabelShuffle {
+        let nobs = group_sizes.iter().sum::<usize>();
+        for order in shuffles.iter() {
+            assert!(order.len() == nobs, "label shuffle has {} entries, expected {}", order.len(), nobs);
+        }
+        LabelShuffle { group_sizes, shuffles }
+    }
+
+    pub fn generate(group_sizes: Vec<usize>, n: i32, seed: &[usize]) -> LabelShuffle {
+        let shuffles = generate_label_shuffles(group_sizes.iter().sum(), n, seed);
+        LabelShuffle::new(group_sizes, shuffles)
+    }
+}
+
+impl PermutationScheme for LabelShuffle {
+    fn group_sizes(&self) -> Vec<usize> {
+        self.group_sizes.clone()
+    }
+
+    fn permutation_count(&self) -> usize {
+        self.shuffles.len()
+    }
+
+    fn order(&self, i: usize) -> Vec<usize> {
+        self.shuffles[i].clone()
+    }
+}
+
+// observations are all conditions of first subject, followed by all conditions
+// of second subject etc.; conditions are only reordered within subject and
+// every subject is passed to statistic as separate group
+#[derive(Debug, Clone)]
+pub struct WithinSubjectShuffle {
+    pub nsubj: usize,
+    pub nconditions: usize,
+    pub shuffles: Vec<Vec<usize>>
+}
+
+impl WithinSubjectShuffle {
+    pub fn new(nsubj: usize, nconditions: usize, shuffles: Vec<Vec<usize>>) -> WithinSubjectShuffle {
+        for order in shuffles.iter() {
+            assert!(order.len() == nsubj * nconditions, "within-subject shuffle has {} entries, expected {}", order.len(), nsubj * nconditions);
+            assert!(
+                order.iter().enumerate().all(|(i, &o)| i / nconditions == o / nconditions),
+                "within-subject shuffle moves observations between subjects"
+            );
+        }
+        WithinSubjectShuffle { nsubj, nconditions, shuffles }
+    }
+
+    pub fn generate(nsubj: usize, nconditions: usize, n: i32, seed: &[usize]) -> WithinSubjectShuffle {
+        WithinSubjectShuffle::new(nsubj, nconditions, generate_within_subject_shuffles(nsubj, nconditions, n, seed))
+    }
+}
+
+impl PermutationScheme for WithinSubjectShuffle {
+    fn group_sizes(&self) -> Vec<usize> {
+        vec![self.nconditions; self.nsubj]
+    }
+
+    fn permutation_count(&self) -> usize {
+        self.shuffles.len()
+    }
+
+    fn order(&self, i: usize) -> Vec<usize> {
+        self.shuffles[i].clone()
+    }
+}
+
+// observations are only exchanged as allowed by exchangeability blocks,
+// which are given for every pooled observation
+#[derive(Debug, Clone)]
+pub struct BlockShuffle {
+    pub group_sizes: Vec<usize>,
+    pub blocks: ExchangeabilityBlocks,
+    pub shuffles: Vec<Vec<usize>>
+}
+
+impl BlockShuffle {
+    pub fn new(group_sizes: Vec<usize>, blocks: ExchangeabilityBlocks, shuffles: Vec<Vec<usize>>) -> BlockShuffle {
+        assert!(
+            blocks.len() == group_sizes.iter().sum::<usize>(),
+            "exchangeability blocks have {} entries, expected one per observation", blocks.len()
+        );
+        for order in shuffles.iter() {
+            assert!(order.len() == blocks.len(), "block shuffle has {} entries, expected {}", order.len(), blocks.len());
+            assert!(blocks.allows_shuffle(order), "block shuffle is not allowed by exchangeability blocks");
+        }
+        BlockShuffle { group_sizes, blocks, shuffles }
+    }
+
+    pub fn generate(group_sizes: Vec<usize>, blocks: ExchangeabilityBlocks, n: i32, seed: &[usize]) -> BlockShuffle {
+        let shuffles = blocks.generate_shuffles(n, seed);
+        BlockShuffle::new(group_sizes, blocks, shuffles)
+    }
+}
+
+impl PermutationScheme for BlockShuffle {
+    fn group_sizes(&self) -> Vec<usize> {
+        self.group_sizes.clone()
+    }
+
+    fn permutation_count(&self) -> usize {
+        self.shuffles.len()
+    }
+
+    fn order(&self, i: usize) -> Vec<usize> {
+        self.shuffles[i].clone()
+    }
+}
+
+// max-statistic permutation test: statistic map of every permutation is passed
+// through enhance (e.g. tfce) before taking its maximum
+pub fn run_scheme<P: PermutationScheme + ?Sized, S: Statistic + ?Sized>(
+    observations: &Vec<Vec<f64>>,
+    scheme: &P,
+    alpha: f64,
+    statistic: &S,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+) -> PermutationResult {
+    run_scheme_batch(observations, scheme, alpha, statistic, &mut |s| vec![enhance(s)]).remove(0)
+}
+
+// enhance returns several maps of one statistic map (e.g. tfce with different
+// parameters), every map gets its own null distribution and result
+pub fn run_scheme_batch<P: PermutationScheme + ?Sized, S: Statistic + ?Sized>(
+    observations: &Vec<Vec<f64>>,
+    scheme: &P,
+    alpha: f64,
+    statistic: &S,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<Vec<f64>>
+) -> Vec<PermutationResult> {
+    let group_sizes = scheme.group_sizes();
+    let nobs = observations.len();
+    assert!(
+        group_sizes.iter().sum::<usize>() == nobs,
+        "permutation scheme expects {} observations, got {}", group_sizes.iter().sum::<usize>(), nobs
+    );
+
+    let mut grouped = |order: &Vec<usize>| {
+        assert!(order.len() == nobs, "permutation has {} entries, expected {}", order.len(), nobs);
+        let mut groups = Vec::with_capacity(group_sizes.len());
+        let mut start = 0;
+        for &size in group_sizes.iter() {
+            groups.push(order[start..(start + size)].iter().map(|&i| &observations[i]).collect());
+            start += size;
+        }
+        enhance(statistic.compute(&groups))
+    };
+
+    let observed = grouped(&(0..nobs).collect());
+
+    let distributions = max_statistic_distributions(
+        &observed, scheme.permutation_count(),
+        &mut |i| {
+            let order = scheme.order(i);
+            if order.iter().enumerate().all(|(i, &o)| i == o) {
+                None
+            } else {
+                Some(grouped(&order))
+            }
+        }
+    );
+
+    observed.into_iter().zip(distributions.into_iter())
+        .map(|(statistic, distribution)| max_statistic_result(statistic, distribution, alpha, scheme.is_exhaustive()))
+        .collect()
+}
+
+pub fn run_permutation<S: Statistic + ?Sized>(
     a: &Vec<Vec<f64>>,
     b: &Vec<Vec<f64>>,
-    n: i32,
-    op: &mut FnMut(Vec<&Vec<f64>>, Vec<&Vec<f64>>) -> Vec<f64>
-) -> Vec<bool> {
-    let nsubj = a.len();
-    let mut rng = StdRng::from_seed(&[17556, 31771, 29830, 29830]);
-
-    let mut distribution = Vec::with_capacity(n as usize);
-
-    for i in 0..n {
-        eprintln!("n = {:4}/{}", i+1, n);
-        let mut permuted_a = Vec::with_capacity(nsubj);
-        let mut permuted_b = Vec::with_capacity(nsubj);
-        for (sa, sb) in a.iter().zip(b.iter()) {
-            if rng.gen() {
-                permuted_a.push(sa);
-                permuted_b.push(sb);
-            } else {
-                permuted_a.push(sb);
-                permuted_b.push(sa);
+    sign_flips: &SignFlips,
+    alpha: f64,
+    statistic: &S,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+) -> PermutationResult {
+    assert!(a.len() == b.len(), "paired data must have same number of subjects in both conditions");
+    let observations = a.iter().chain(b.iter()).cloned().collect();
+    run_scheme(&observations, &SignFlip::new(a.len(), sign_flips.clone()), alpha, statistic, enhance)
+}
+
+// sign flips are applied to deviations of subject data from popmean,
+// statistic receives single group
+pub fn run_one_sample_permutation<S: Statistic + ?Sized>(
+    a: &Vec<Vec<f64>>,
+    popmean: f64,
+    sign_flips: &SignFlips,
+    alpha: f64,
+    statistic: &S,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+) -> PermutationResult {
+    let mirrored =
+        a.iter().map(|sa| sa.iter().map(|&v| 2.0 * popmean - v).collect())
+        .collect::<Vec<Vec<f64>>>();
+    run_permutation(a, &mirrored, sign_flips, alpha, &FirstGroup(statistic), enhance)
+}
+
+struct FirstGroup<'a, S: Statistic + ?Sized + 'a>(&'a S);
+
+impl<'a, S: Statistic + ?Sized> Statistic for FirstGroup<'a, S> {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        self.0.compute(&vec![groups[0].clone()])
+    }
+}
+
+// Freedman-Lane: residuals of the nuisance-only model are permuted (label shuffles)
+// or sign flipped, and added back to the part of the data explained by nuisance
+pub fn run_freedman_lane_permutation(
+    glm: &Glm,
+    y: &Vec<Vec<f64>>,
+    permutations: &Permutations,
+    alpha: f64,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+) -> PermutationResult {
+    let nobs = y.len();
+    assert!(nobs == glm.observation_count(), "data has {} observations, design matrix has {} rows", nobs, glm.observation_count());
+    let (fitted, residuals) = glm.nuisance_split(y);
+    let statistic = FreedmanLane { glm, fitted: &fitted };
+
+    match *permutations {
+        Permutations::SignFlips(ref sign_flips) => {
+            let negated = residuals.iter().map(|r| r.iter().map(|v| -v).collect()).collect::<Vec<Vec<f64>>>();
+            let observations = residuals.into_iter().chain(negated.into_iter()).collect();
+            run_scheme(&observations, &SignFlip::new(nobs, sign_flips.clone()), alpha, &FirstGroup(&statistic), enhance)
+        },
+        Permutations::LabelShuffles(ref shuffles) =>
+            run_scheme(&residuals, &LabelShuffle::new(vec![nobs], shuffles.clone()), alpha, &statistic, enhance)
+    }
+}
+
+// glm statistic of permuted residuals added to nuisance fit
+struct FreedmanLane<'a> {
+    glm: &'a Glm,
+    fitted: &'a Vec<Vec<f64>>
+}
+
+impl<'a> Statistic for FreedmanLane<'a> {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        let y = self.fitted.iter().zip(groups[0].iter())
+            .map(|(f, r)| f.iter().zip(r.iter()).map(|(fv, rv)| fv + rv).collect())
+            .collect::<Vec<Vec<f64>>>();
+        self.glm.compute(&vec![y.iter().collect()])
+    }
+}
+
+// data is indexed by subject and condition; shuffles are permutations of all
+// observations in subject-major order that only reorder conditions within subjects;
+// statistic receives one group per subject
+pub fn run_within_subject_permutation<S: Statistic + ?Sized>(
+    data: &Vec<Vec<Vec<f64>>>,
+    shuffles: &Vec<Vec<usize>>,
+    alpha: f64,
+    statistic: &S,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+) -> PermutationResult {
+    let scheme = WithinSubjectShuffle::new(data.len(), data[0].len(), shuffles.clone());
+    let observations = data.iter().flat_map(|conditions| conditions.iter().cloned()).collect();
+    run_scheme(&observations, &scheme, alpha, statistic, enhance)
+}
+
+// shuffles are permutations of pooled a and b observations, first a.len()
+// observations of every shuffle are assigned to group a, the rest to group b
+pub fn run_label_permutation<S: Statistic + ?Sized>(
+    a: &Vec<Vec<f64>>,
+    b: &Vec<Vec<f64>>,
+    shuffles: &Vec<Vec<usize>>,
+    alpha: f64,
+    statistic: &S,
+    enhance: &mut FnMut(Vec<f64>) -> Vec<f64>
+) -> PermutationResult {
+    let scheme = LabelShuffle::new(vec![a.len(), b.len()], shuffles.clone());
+    let observations = a.iter().chain(b.iter()).cloned().collect();
+    run_scheme(&observations, &scheme, alpha, statistic, enhance)
+}
+
+fn max_statistic_distributions(
+    statistics: &Vec<Vec<f64>>,
+    permutations: usize,
+    op: &mut FnMut(usize) -> Option<Vec<Vec<f64>>>
+) -> Vec<Vec<f64>> {
+    // observed labelling is always part of the null distribution, so p-values
+    // can't go below 1/(n+1); identity permutations are represented by it
+    // and op returns None for them
+    let mut distributions = statistics.iter().map(|statistic| {
+        let mut distribution = Vec::with_capacity(permutations + 1);
+        distribution.push(statistic.iter().map(|v| v.abs()).fold(0.0, f64::max));
+        distribution
+    }).collect::<Vec<Vec<f64>>>();
+
+    for i in 0..permutations {
+        eprintln!("n = {:4}/{}", i+1, permutations);
+        if let Some(maps) = op(i) {
+            for (distribution, values) in distributions.iter_mut().zip(maps.into_iter()) {
+                distribution.push(values.into_iter().map(f64::abs).fold(0.0, f64::max));
+            }
+        }
+    }
+
+    distributions
+}
+
+pub fn generate_sign_flips(nsubj: usize, n: i32, seed: &[usize]) -> SignFlips {
+    // with few subjects random flips would repeat some patterns and skip others,
+    // so enumerate all of them instead to get an exact test
+    if nsubj < 32 && (n as u64) >= (1u64 << nsubj) {
+        eprintln!("{} permutations requested, using all {} sign flips of {} subjects", n, 1u64 << nsubj, nsubj);
+        return enumerate_sign_flips(nsubj);
+    }
+
+    let mut rng = StdRng::from_seed(seed);
+    let mut sign_flips = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let mut flips = Vec::with_capacity(nsubj);
+        for _ in 0..nsubj {
+            flips.push(!rng.gen::<bool>());
+        }
+        sign_flips.push(flips);
+    }
+    SignFlips::sampled(sign_flips)
+}
+
+pub fn enumerate_sign_flips(nsubj: usize) -> SignFlips {
+    assert!(nsubj < 32, "too many subjects to enumerate all sign flips");
+    let n = 1usize << nsubj;
+    let mut sign_flips = Vec::with_capacity(n);
+    for pattern in 0..n {
+        sign_flips.push((0..nsubj).map(|s| pattern & (1 << s) != 0).collect());
+    }
+    SignFlips { flips: sign_flips, exhaustive: true }
+}
+
+// sign flip matrix file contains one permutation per line,
+// with 1 (keep) or -1 (flip) for every subject, separated by whitespace
+pub fn read_sign_flips(filename: &str) -> Vec<Vec<bool>> {
+    let file = File::open(&filename).expect(&format!("failed to open sign flip file {}", &filename));
+    let mut sign_flips = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("failed to read sign flip file");
+        if line.trim().is_empty() {
+            continue;
+        }
+        sign_flips.push(
+            line.split_whitespace().map(|v| match v {
+                "1" => false,
+                "-1" => true,
+                _ => panic!("unexpected value in sign flip file: {}", v)
+            }).collect()
+        );
+    }
+    sign_flips
+}
+
+pub fn write_sign_flips(filename: &str, sign_flips: &Vec<Vec<bool>>) {
+    let mut file = File::create(&filename).expect(&format!("failed to create sign flip file {}", &filename));
+    for flips in sign_flips.iter() {
+        let row = flips.iter().map(|&flip| if flip { "-1" } else { "1" }).collect::<Vec<&str>>();
+        writeln!(file, "{}", row.join(" ")).unwrap();
+    }
+}
+
+pub fn generate_label_shuffles(nobs: usize, n: i32, seed: &[usize]) -> Vec<Vec<usize>> {
+    let mut rng = StdRng::from_seed(seed);
+    let mut shuffles = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let mut order = (0..nobs).collect::<Vec<usize>>();
+        rng.shuffle(&mut order);
+        shuffles.push(order);
+    }
+    shuffles
+}
+
+pub fn generate_within_subject_shuffles(nsubj: usize, nconditions: usize, n: i32, seed: &[usize]) -> Vec<Vec<usize>> {
+    let mut rng = StdRng::from_seed(seed);
+    let mut shuffles = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let mut order = Vec::with_capacity(nsubj * nconditions);
+        for s in 0..nsubj {
+            let mut conditions = (s * nconditions..(s + 1) * nconditions).collect::<Vec<usize>>();
+            rng.shuffle(&mut conditions);
+            order.extend(conditions);
+        }
+        shuffles.push(order);
+    }
+    shuffles
+}
+
+// within-subject shuffles that are exchangeable under the null hypothesis of
+// tested effect: levels of factor A and B are permuted within every subject,
+// so the condition grid of subject is only reordered by rows and columns; main
+// effects permute levels of their own factor only, so that the other main
+// effect is not mixed into the null distribution, interaction permutes both,
+// which leaves subject main effects additive and so out of interaction F
+pub fn generate_rm_anova_shuffles(anova: &RmAnova, nsubj: usize, n: i32, seed: &[usize]) -> Vec<Vec<usize>> {
+    let la = anova.levels_a;
+    let lb = anova.levels_b;
+    let mut rng = StdRng::from_seed(seed);
+    let mut shuffles = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let mut order = Vec::with_capacity(nsubj * la * lb);
+        for s in 0..nsubj {
+            let mut levels_a = (0..la).collect::<Vec<usize>>();
+            let mut levels_b = (0..lb).collect::<Vec<usize>>();
+            if anova.effect != Effect::MainB {
+                rng.shuffle(&mut levels_a);
+            }
+            if anova.effect != Effect::MainA {
+                rng.shuffle(&mut levels_b);
+            }
+            for ia in 0..la {
+                for ib in 0..lb {
+                    order.push(s * la * lb + levels_a[ia] * lb + levels_b[ib]);
+                }
             }
         }
-        distribution.push(op(permuted_a, permuted_b).into_iter().fold(0.0, f64::max));
+        shuffles.push(order);
     }
+    shuffles
+}
+
+// label shuffle file contains one permutation per line, with 1-based
+// indices of pooled observations separated by whitespace
+pub fn read_label_shuffles(filename: &str) -> Vec<Vec<usize>> {
+    let file = File::open(&filename).expect(&format!("failed to open label shuffle file {}", &filename));
+    let mut shuffles = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("failed to read label shuffle file");
+        if line.trim().is_empty() {
+            continue;
+        }
+        let order = line.split_whitespace().map(|v| {
+            v.parse::<usize>().ok().and_then(|i| i.checked_sub(1))
+                .expect(&format!("unexpected value in label shuffle file: {}", v))
+        }).collect::<Vec<usize>>();
+        let mut sorted = order.clone();
+        sorted.sort();
+        if sorted.into_iter().enumerate().any(|(i, o)| i != o) {
+            panic!("label shuffle file contains a line that is not a permutation: {}", line);
+        }
+        shuffles.push(order);
+    }
+    shuffles
+}
+
+pub fn write_label_shuffles(filename: &str, shuffles: &Vec<Vec<usize>>) {
+    let mut file = File::create(&filename).expect(&format!("failed to create label shuffle file {}", &filename));
+    for order in shuffles.iter() {
+        let row = order.iter().map(|&i| (i + 1).to_string()).collect::<Vec<String>>();
+        writeln!(file, "{}", row.join(" ")).unwrap();
+    }
+}
 
+fn max_statistic_result(
+    statistic: Vec<f64>,
+    mut distribution: Vec<f64>,
+    alpha: f64,
+    exhaustive: bool
+) -> PermutationResult {
+    assert!(alpha > 0.0 && alpha < 1.0, "alpha must be between 0 and 1");
     distribution.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    let q95 = distribution[((n as f64) * 0.95).floor() as usize];
-    op(a.iter().collect(), b.iter().collect()).into_iter().map(|v| v >= q95).collect()
+    let n = distribution.len();
+    // value is significant if at most alpha*n of null maxima are at least as large,
+    // i.e. if it is strictly greater than the threshold
+    let allowed_exceedances = ((n as f64) * alpha + 1e-9).floor() as usize;
+    let threshold = distribution[n - allowed_exceedances - 1];
+    // statistic maps are signed, inference is done on effect magnitude
+    let significant = statistic.iter().map(|&v| v.abs() > threshold).collect();
+    let p_values = statistic.iter().map(|&v| corrected_p_value(&distribution, v.abs())).collect::<Vec<f64>>();
+
+    // monte carlo standard error of p-values, zero when all permutations were enumerated
+    let p_value_standard_errors =
+        p_values.iter().map(|&p| {
+            if exhaustive { 0.0 } else { (p * (1.0 - p) / (n as f64)).sqrt() }
+        }).collect();
+
+    PermutationResult {
+        statistic,
+        null_distribution: distribution,
+        threshold,
+        significant,
+        p_values,
+        p_value_standard_errors
+    }
+}
+
+// fraction of the (sorted) max-statistic null distribution that is at least as large as value
+fn corrected_p_value(distribution: &Vec<f64>, value: f64) -> f64 {
+    let mut lo = 0;
+    let mut hi = distribution.len();
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if distribution[mid] < value {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    ((distribution.len() - lo) as f64) / (distribution.len() as f64)
 }
 
 pub fn significant_indices(perm_result: &Vec<bool>) -> Vec<usize> {
@@ -62,3 +709,309 @@ pub fn get_periods(indices: Vec<usize>) -> Vec<(usize, usize)> {
     }
     periods
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_corrected_p_value() {
+        let distribution = vec![1.0, 2.0, 2.0, 3.0, 4.0];
+        assert_eq!(corrected_p_value(&distribution, 0.5), 1.0);
+        assert_eq!(corrected_p_value(&distribution, 2.0), 0.8);
+        assert_eq!(corrected_p_value(&distribution, 3.5), 0.2);
+        assert_eq!(corrected_p_value(&distribution, 5.0), 0.0);
+    }
+
+    #[test]
+    fn test_run_permutation_result() {
+        let a = vec![vec![1.0, 0.0], vec![2.0, 0.0], vec![3.0, 0.0]];
+        let b = vec![vec![0.0, 0.0], vec![0.0, 0.0], vec![0.0, 0.0]];
+        let sign_flips = generate_sign_flips(3, 20, &DEFAULT_SEED);
+        let result = run_permutation(&a, &b, &sign_flips, 0.05, &|groups: &Vec<Vec<&Vec<f64>>>| {
+            (0..2).map(|i| groups[0].iter().zip(groups[1].iter()).map(|(sa, sb)| sa[i] - sb[i]).sum()).collect()
+        }, &mut |s| s);
+        assert_eq!(result.statistic, vec![6.0, 0.0]);
+        assert_eq!(result.null_distribution, vec![0.0, 0.0, 2.0, 2.0, 4.0, 4.0, 6.0, 6.0]);
+        assert_eq!(result.p_values, vec![0.25, 1.0]);
+        assert_eq!(result.p_value_standard_errors, vec![0.0, 0.0]);
+        assert_eq!(result.significant, vec![false, false]);
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let a = vec![vec![1.0, 0.0], vec![2.0, 0.5], vec![3.0, -1.0]];
+        let b = vec![vec![0.0; 2]; 3];
+        let permutations = Permutations::SignFlips(generate_sign_flips(3, 20, &DEFAULT_SEED));
+        let sum = |groups: &Vec<Vec<&Vec<f64>>>| -> Vec<f64> {
+            (0..2).map(|i| groups[0].iter().zip(groups[1].iter()).map(|(sa, sb)| sa[i] - sb[i]).sum()).collect()
+        };
+        let results = permutations.run_batch(&a, &b, 0.05, &sum, &mut |s| vec![s.clone(), s.iter().map(|v| v * v).collect()]);
+        assert_eq!(results.len(), 2);
+        let identity = permutations.run(&a, &b, 0.05, &sum, &mut |s| s);
+        let squared = permutations.run(&a, &b, 0.05, &sum, &mut |s| s.iter().map(|v| v * v).collect());
+        assert_eq!(results[0].null_distribution, identity.null_distribution);
+        assert_eq!(results[1].null_distribution, squared.null_distribution);
+        assert_eq!(results[1].p_values, squared.p_values);
+    }
+
+    #[test]
+    fn test_sign_flips_seed() {
+        assert_eq!(generate_sign_flips(5, 10, &[1, 2, 3]), generate_sign_flips(5, 10, &[1, 2, 3]));
+        assert!(generate_sign_flips(5, 10, &[1, 2, 3]) != generate_sign_flips(5, 10, &[1, 2, 4]));
+    }
+
+    #[test]
+    fn test_enumerate_sign_flips() {
+        let sign_flips = enumerate_sign_flips(3);
+        assert_eq!(sign_flips.len(), 8);
+        assert_eq!(sign_flips[0], vec![false, false, false]);
+        for i in 0..8 {
+            for j in (i + 1)..8 {
+                assert!(sign_flips[i] != sign_flips[j]);
+            }
+        }
+        assert_eq!(generate_sign_flips(3, 8, &DEFAULT_SEED), sign_flips);
+        assert_eq!(generate_sign_flips(3, 1000, &DEFAULT_SEED), sign_flips);
+        assert_eq!(generate_sign_flips(3, 7, &DEFAULT_SEED).len(), 7);
+    }
+
+    #[test]
+    fn test_sign_flips_file_roundtrip() {
+        let filename = env::temp_dir().join("libtfce_test_sign_flips.txt");
+        let filename = filename.to_str().unwrap();
+        let sign_flips = generate_sign_flips(7, 15, &DEFAULT_SEED);
+        write_sign_flips(filename, &sign_flips);
+        assert_eq!(read_sign_flips(filename), sign_flips.flips);
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_observed_in_null_distribution() {
+        let a = vec![vec![1.0], vec![2.0], vec![3.0], vec![4.0], vec![5.0]];
+        let b = vec![vec![0.0], vec![0.0], vec![0.0], vec![0.0], vec![0.0]];
+        let sign_flips = SignFlips::sampled(vec![
+            vec![false, false, false, false, true],
+            vec![false, false, false, true, false],
+            vec![false, false, false, false, false]
+        ]);
+        let result = run_permutation(&a, &b, &sign_flips, 0.05, &|groups: &Vec<Vec<&Vec<f64>>>| {
+            vec![groups[0].iter().zip(groups[1].iter()).map(|(sa, sb)| sa[0] - sb[0]).sum()]
+        }, &mut |s| s);
+        assert_eq!(result.null_distribution, vec![5.0, 7.0, 15.0]);
+        assert_eq!(result.p_values, vec![1.0 / 3.0]);
+        assert!((result.p_value_standard_errors[0] - (2.0f64 / 27.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_run_one_sample_permutation() {
+        let a = vec![vec![1.5, 0.5], vec![2.0, 0.0], vec![0.0, 1.0]];
+        let sign_flips = SignFlips::sampled(vec![vec![true, false, false], vec![false, true, true]]);
+        let mut permuted = Vec::new();
+        // statistic passes data through, enhance records it
+        let flatten = |groups: &Vec<Vec<&Vec<f64>>>| {
+            assert_eq!(groups.len(), 1);
+            groups[0].iter().flat_map(|sa| sa.iter().cloned()).collect()
+        };
+        let result = run_one_sample_permutation(&a, 1.0, &sign_flips, 0.05, &flatten, &mut |values| {
+            let sum = (0..3).map(|s| values[2 * s] - 1.0).sum();
+            permuted.push(values);
+            vec![sum]
+        });
+        assert_eq!(permuted[1], vec![0.5, 1.5, 2.0, 0.0, 0.0, 1.0]);
+        assert_eq!(permuted[2], vec![1.5, 0.5, 0.0, 2.0, 2.0, 1.0]);
+        assert_eq!(result.statistic, vec![0.5]);
+        assert_eq!(result.null_distribution, vec![0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_run_freedman_lane_permutation() {
+        use ::glm::Contrast;
+        let design = vec![
+            vec![1.0, 0.5], vec![1.0, -1.0], vec![1.0, 2.0], vec![1.0, 0.0], vec![1.0, 1.5]
+        ];
+        let y = vec![vec![3.0], vec![1.0], vec![4.0], vec![1.0], vec![5.0]];
+        let glm = Glm::new(design, Contrast::T(vec![0.0, 1.0]));
+        let shuffles = Permutations::LabelShuffles(vec![vec![0, 1, 2, 3, 4], vec![4, 3, 2, 1, 0]]);
+        let mut permuted = Vec::new();
+        let result = run_freedman_lane_permutation(&glm, &y, &shuffles, 0.05, &mut |t| {
+            permuted.push(t[0]);
+            t
+        });
+        assert!((permuted[0] - glm.statistic(&y.iter().collect())[0]).abs() < 1e-10);
+        // intercept-only model fits the mean, residuals are reversed
+        let expected = vec![vec![5.0], vec![1.0], vec![4.0], vec![1.0], vec![3.0]];
+        assert!((permuted[1] - glm.statistic(&expected.iter().collect())[0]).abs() < 1e-10);
+        assert_eq!(result.null_distribution.len(), 2);
+    }
+
+    #[test]
+    fn test_run_within_subject_permutation() {
+        let data = vec![
+            vec![vec![1.0], vec![2.0], vec![3.0]],
+            vec![vec![4.0], vec![5.0], vec![6.0]]
+        ];
+        let shuffles = generate_within_subject_shuffles(2, 3, 10, &DEFAULT_SEED);
+        assert!(shuffles.iter().all(|order| order[..3].iter().all(|&o| o < 3) && order[3..].iter().all(|&o| o >= 3)));
+        let result = run_within_subject_permutation(&data, &shuffles, 0.05, &|data: &Vec<Vec<&Vec<f64>>>| {
+            assert_eq!(data.len(), 2);
+            assert_eq!(data[0].iter().map(|c| c[0]).sum::<f64>(), 6.0);
+            assert_eq!(data[1].iter().map(|c| c[0]).sum::<f64>(), 15.0);
+            vec![data[0][0][0] + data[1][0][0]]
+        }, &mut |s| s);
+        assert_eq!(result.statistic, vec![5.0]);
+    }
+
+    #[test]
+    fn test_rm_anova_shuffles_keep_other_factor() {
+        let anova = RmAnova::two_way(2, 3, Effect::MainA);
+        for order in generate_rm_anova_shuffles(&anova, 2, 10, &DEFAULT_SEED).iter() {
+            assert!(order.iter().enumerate().all(|(i, &o)| i / 6 == o / 6 && i % 3 == o % 3));
+        }
+        let anova = RmAnova::two_way(2, 3, Effect::MainB);
+        for order in generate_rm_anova_shuffles(&anova, 2, 10, &DEFAULT_SEED).iter() {
+            assert!(order.iter().enumerate().all(|(i, &o)| i / 6 == o / 6 && (i % 6) / 3 == (o % 6) / 3));
+        }
+    }
+
+    #[test]
+    fn test_rm_anova_false_positive_rate() {
+        // strong main effect of B that varies between subjects, no effect of A
+        // and no interaction: tests of A and interaction should reject at about alpha
+        let mut rng = StdRng::from_seed(&DEFAULT_SEED);
+        let nexperiments = 400;
+        for &effect in [Effect::MainA, Effect::Interaction].iter() {
+            let anova = RmAnova::two_way(2, 2, effect);
+            let mut rejections = 0;
+            for _ in 0..nexperiments {
+                let data = (0..6).map(|_| {
+                    let slope = 5.0 + rng.gen::<f64>() * 50.0;
+                    (0..4).map(|c| vec![slope * ((c % 2) as f64) + rng.gen::<f64>()]).collect()
+                }).collect::<Vec<Vec<Vec<f64>>>>();
+                let seed = [rng.gen::<usize>()];
+                let shuffles = generate_rm_anova_shuffles(&anova, 6, 200, &seed);
+                let result = run_within_subject_permutation(&data, &shuffles, 0.05, &anova, &mut |s| s);
+                if result.p_values[0] <= 0.05 {
+                    rejections += 1;
+                }
+            }
+            let rate = (rejections as f64) / (nexperiments as f64);
+            assert!(rate > 0.02 && rate < 0.075, "false positive rate of {:?} is {}", effect, rate);
+        }
+    }
+
+    #[test]
+    fn test_statistic_with_state() {
+        // covariate shuffling is done by reordering subjects against fixed covariate
+        struct Dot(Vec<f64>);
+        impl Statistic for Dot {
+            fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+                vec![groups[0].iter().zip(self.0.iter()).map(|(d, c)| d[0] * c).sum()]
+            }
+        }
+        let data = vec![vec![1.0], vec![2.0], vec![3.0]];
+        let shuffles = vec![vec![0, 1, 2], vec![2, 1, 0], vec![1, 0, 2]];
+        let result = run_label_permutation(&data, &Vec::new(), &shuffles, 0.05, &Dot(vec![10.0, 20.0, 30.0]), &mut |s| s);
+        assert_eq!(result.statistic, vec![140.0]);
+        assert_eq!(result.null_distribution, vec![100.0, 130.0, 140.0]);
+        assert_eq!(result.p_values, vec![1.0 / 3.0]);
+    }
+
+    #[test]
+    fn test_sign_flip_scheme_orders() {
+        let scheme = SignFlip::new(3, SignFlips::sampled(vec![vec![false, true, false], vec![true, true, true]]));
+        assert_eq!(scheme.group_sizes(), vec![3, 3]);
+        assert_eq!(scheme.orders(), vec![vec![0, 4, 2, 3, 1, 5], vec![3, 4, 5, 0, 1, 2]]);
+        assert!(!scheme.is_exhaustive());
+        assert!(SignFlip::new(2, enumerate_sign_flips(2)).is_exhaustive());
+        // same rows from random generator or file are not known to be exhaustive
+        assert!(!SignFlip::new(2, SignFlips::sampled(enumerate_sign_flips(2).flips)).is_exhaustive());
+        assert!(!SignFlip::new(3, generate_sign_flips(3, 7, &DEFAULT_SEED)).is_exhaustive());
+        // subjects 0 and 2 always flipped together
+        let whole = ExchangeabilityBlocks::whole(vec![0, 1, 0, 1]).generate_sign_flips(4, &DEFAULT_SEED);
+        assert!(SignFlip::new(4, whole.clone()).is_exhaustive());
+        assert!(!SignFlip::new(4, ExchangeabilityBlocks::whole(vec![0, 1, 0, 1]).generate_sign_flips(3, &DEFAULT_SEED)).is_exhaustive());
+    }
+
+    #[test]
+    fn test_block_shuffle_scheme() {
+        let blocks = vec![0, 0, 1, 1, 1, 0];
+        let scheme = BlockShuffle::generate(vec![3, 3], ExchangeabilityBlocks::within(blocks.clone()), 20, &DEFAULT_SEED);
+        for order in scheme.orders() {
+            assert!(order.iter().enumerate().all(|(i, &o)| blocks[i] == blocks[o]));
+        }
+        assert!(scheme.orders().iter().any(|order| order.iter().enumerate().any(|(i, &o)| i != o)));
+
+        // shuffling only within blocks keeps block sums of every group position
+        let observations = (0..6).map(|i| vec![i as f64]).collect();
+        let result = run_scheme(&observations, &scheme, 0.05, &|groups: &Vec<Vec<&Vec<f64>>>| {
+            vec![groups[0].iter().chain(groups[1].iter()).enumerate()
+                .filter(|&(i, _)| blocks[i] == 1).map(|(_, o)| o[0]).sum()]
+        }, &mut |s| s);
+        assert!(result.null_distribution.iter().all(|&v| v == 9.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_block_shuffle_rejects_crossing_blocks() {
+        BlockShuffle::new(vec![2], ExchangeabilityBlocks::within(vec![0, 1]), vec![vec![1, 0]]);
+    }
+
+    #[test]
+    fn test_run_label_permutation() {
+        let a = vec![vec![5.0], vec![6.0]];
+        let b = vec![vec![1.0], vec![2.0], vec![3.0]];
+        let shuffles = vec![
+            vec![0, 1, 2, 3, 4],
+            vec![2, 3, 0, 1, 4],
+            vec![4, 1, 2, 3, 0]
+        ];
+        let result = run_label_permutation(&a, &b, &shuffles, 0.05, &|groups: &Vec<Vec<&Vec<f64>>>| {
+            assert_eq!(groups[0].len(), 2);
+            assert_eq!(groups[1].len(), 3);
+            vec![groups[0].iter().map(|s| s[0]).sum::<f64>() - groups[1].iter().map(|s| s[0]).sum::<f64>()]
+        }, &mut |s| s);
+        assert_eq!(result.statistic, vec![5.0]);
+        assert_eq!(result.null_distribution, vec![1.0, 5.0, 11.0]);
+        assert_eq!(result.p_values, vec![2.0 / 3.0]);
+    }
+
+    #[test]
+    fn test_label_shuffles_file_roundtrip() {
+        let filename = env::temp_dir().join("libtfce_test_label_shuffles.txt");
+        let filename = filename.to_str().unwrap();
+        let shuffles = generate_label_shuffles(9, 15, &DEFAULT_SEED);
+        assert!(shuffles.iter().all(|order| {
+            let mut sorted = order.clone();
+            sorted.sort();
+            sorted == (0..9).collect::<Vec<usize>>()
+        }));
+        write_label_shuffles(filename, &shuffles);
+        assert_eq!(read_label_shuffles(filename), shuffles);
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_alpha_threshold() {
+        let statistic = vec![0.0, 5.5, 9.5];
+        let distribution = (0..10).map(|v| v as f64).rev().collect();
+        let result = max_statistic_result(statistic.clone(), distribution, 0.05, false);
+        assert_eq!(result.threshold, 9.0);
+        assert_eq!(result.significant, vec![false, false, true]);
+        let distribution = (0..10).map(|v| v as f64).collect();
+        let result = max_statistic_result(statistic, distribution, 0.5, false);
+        assert_eq!(result.threshold, 4.0);
+        assert_eq!(result.significant, vec![false, true, true]);
+    }
+
+    #[test]
+    fn test_signed_statistic() {
+        let statistic = vec![-9.5, 5.5, -1.0];
+        let distribution = (0..10).map(|v| v as f64).collect();
+        let result = max_statistic_result(statistic, distribution, 0.05, false);
+        assert_eq!(result.significant, vec![true, false, false]);
+        assert_eq!(result.p_values, vec![0.0, 0.4, 0.9]);
+    }
+}
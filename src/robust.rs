@@ -0,0 +1,210 @@
+use ::correlation::rank;
+use ::statistic::Statistic;
+
+// proportion of differences trimmed from each end in yuen test
+pub const DEFAULT_TRIM: f64 = 0.2;
+
+// paired statistics used in place of paired t-test where outlier subjects
+// are a concern; yuen carries trim proportion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairedStatistic {
+    Wilcoxon,
+    Yuen(f64),
+    MedianDifference
+}
+
+impl PairedStatistic {
+    pub fn compute(&self, a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>) -> Vec<f64> {
+        match *self {
+            PairedStatistic::Wilcoxon => wilcoxon_vec(a, b),
+            PairedStatistic::Yuen(trim) => yuen_vec(a, b, trim),
+            PairedStatistic::MedianDifference => median_difference_vec(a, b)
+        }
+    }
+}
+
+// groups are conditions a and b of same subjects
+impl Statistic for PairedStatistic {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        PairedStatistic::compute(self, &groups[0], &groups[1])
+    }
+}
+
+pub fn wilcoxon(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+    let (a, b) = as_voxels(a, b);
+    wilcoxon_vec(&a.iter().collect(), &b.iter().collect())[0]
+}
+
+// wilcoxon signed-rank statistic as z value, normal approximation with tie correction;
+// zero differences are dropped
+pub fn wilcoxon_vec(a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>) -> Vec<f64> {
+    let nsubj = a.len();
+    let n = a[0].len();
+
+    let mut result = Vec::with_capacity(n);
+    let mut diff = Vec::with_capacity(nsubj);
+    let mut i = 0;
+    while i < n {
+        diff.clear();
+        for s in 0..nsubj {
+            let d = a[s][i] - b[s][i];
+            if d != 0.0 {
+                diff.push(d);
+            }
+        }
+        let ranks = rank(&diff.iter().map(|d| d.abs()).collect());
+
+        let mut w = 0.0;
+        for (d, r) in diff.iter().zip(ranks.iter()) {
+            if *d > 0.0 {
+                w += r;
+            }
+        }
+
+        // ties in absolute differences share rank sums, count them from ranks
+        let mut ties = 0.0;
+        let mut sorted_ranks = ranks.clone();
+        sorted_ranks.sort_by(|x, y| x.partial_cmp(y).unwrap());
+        let mut j = 0;
+        while j < sorted_ranks.len() {
+            let mut k = j;
+            while k + 1 < sorted_ranks.len() && sorted_ranks[k + 1] == sorted_ranks[j] {
+                k += 1;
+            }
+            let t = (k - j + 1) as f64;
+            ties += t*t*t - t;
+            j = k + 1;
+        }
+
+        let nd = diff.len() as f64;
+        let z_value = (w - nd*(nd + 1.0)/4.0) / (nd*(nd + 1.0)*(2.0*nd + 1.0)/24.0 - ties/48.0).sqrt();
+        result.push(if z_value.is_nan() { 0.0 } else { z_value });
+
+        i += 1;
+    }
+    result
+}
+
+pub fn yuen(a: &Vec<f64>, b: &Vec<f64>, trim: f64) -> f64 {
+    let (a, b) = as_voxels(a, b);
+    yuen_vec(&a.iter().collect(), &b.iter().collect(), trim)[0]
+}
+
+// trimmed-mean t of paired differences, standard error from winsorized variance
+pub fn yuen_vec(a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>, trim: f64) -> Vec<f64> {
+    assert!(trim >= 0.0 && trim < 0.5, "trim must be between 0 and 0.5");
+    let nsubj = a.len();
+    let n = a[0].len();
+    let g = (trim * (nsubj as f64)).floor() as usize;
+    let h = nsubj - 2*g;
+    assert!(h >= 2, "too few subjects left after trimming");
+
+    let mut result = Vec::with_capacity(n);
+    let mut diff = Vec::with_capacity(nsubj);
+    let mut i = 0;
+    while i < n {
+        diff.clear();
+        for s in 0..nsubj {
+            diff.push(a[s][i] - b[s][i]);
+        }
+        diff.sort_by(|x, y| x.partial_cmp(y).unwrap());
+
+        let trimmed_mean = diff[g..(nsubj - g)].iter().sum::<f64>() / (h as f64);
+
+        let (low, high) = (diff[g], diff[nsubj - g - 1]);
+        let mut sum = 0.0;
+        let mut sum2 = 0.0;
+        for d in diff.iter() {
+            let w = d.max(low).min(high);
+            sum += w;
+            sum2 += w*w;
+        }
+        let ns = nsubj as f64;
+        let winsorized_var = (sum2 - sum*sum/ns) / (ns - 1.0);
+
+        let t_value = trimmed_mean / (winsorized_var.sqrt() / ((h as f64) / ns * ns.sqrt()));
+        result.push(if t_value.is_nan() { 0.0 } else { t_value });
+
+        i += 1;
+    }
+    result
+}
+
+pub fn median_difference(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+    let (a, b) = as_voxels(a, b);
+    median_difference_vec(&a.iter().collect(), &b.iter().collect())[0]
+}
+
+pub fn median_difference_vec(a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>) -> Vec<f64> {
+    let nsubj = a.len();
+    let n = a[0].len();
+
+    let mut result = Vec::with_capacity(n);
+    let mut diff = Vec::with_capacity(nsubj);
+    let mut i = 0;
+    while i < n {
+        diff.clear();
+        for s in 0..nsubj {
+            diff.push(a[s][i] - b[s][i]);
+        }
+        diff.sort_by(|x, y| x.partial_cmp(y).unwrap());
+        let median =
+            if nsubj % 2 == 1 {
+                diff[nsubj / 2]
+            } else {
+                (diff[nsubj / 2 - 1] + diff[nsubj / 2]) / 2.0
+            };
+        result.push(median);
+
+        i += 1;
+    }
+    result
+}
+
+fn as_voxels(a: &Vec<f64>, b: &Vec<f64>) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+    (a.iter().map(|&v| vec![v]).collect(), b.iter().map(|&v| vec![v]).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ttest::ttest_rel;
+
+    fn data() -> (Vec<f64>, Vec<f64>) {
+        (
+            vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645,  0.30417776,  0.06973489,  0.10810649],
+            vec![ 0.44289209,  0.60141457,  0.06801757,  0.23473256,  0.41713896,  0.30417776,  0.14355822,  0.1778686 ]
+        )
+    }
+
+    #[test]
+    fn test_wilcoxon() {
+        let (a, b) = data();
+        assert!((wilcoxon(&a, &b) - 1.3522468075656264).abs() < 1e-7);
+        assert!((wilcoxon(&b, &a) + 1.3522468075656264).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_yuen() {
+        let (a, b) = data();
+        assert!((yuen(&a, &b, 0.2) - 1.2078899502537817).abs() < 1e-7);
+        // without trimming it is the ordinary paired t-test
+        assert!((yuen(&a, &b, 0.0) - ttest_rel(&a, &b)).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_paired_statistic() {
+        let (a, b) = data();
+        let groups = vec![a.iter().map(|&v| vec![v]).collect::<Vec<Vec<f64>>>(), b.iter().map(|&v| vec![v]).collect()];
+        let groups = groups.iter().map(|g| g.iter().collect()).collect::<Vec<Vec<&Vec<f64>>>>();
+        assert_eq!(Statistic::compute(&PairedStatistic::Wilcoxon, &groups), vec![wilcoxon(&a, &b)]);
+        assert_eq!(Statistic::compute(&PairedStatistic::Yuen(0.2), &groups), vec![yuen(&a, &b, 0.2)]);
+        assert_eq!(Statistic::compute(&PairedStatistic::MedianDifference, &groups), vec![median_difference(&a, &b)]);
+    }
+
+    #[test]
+    fn test_median_difference() {
+        let (a, b) = data();
+        assert!((median_difference(&a, &b) - 0.05195396499999999).abs() < 1e-10);
+    }
+}
@@ -0,0 +1,180 @@
+use ::voxel::Voxel;
+use std::collections::VecDeque;
+use ::statistic::Statistic;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VarianceSmoothing {
+    // average over voxel and its direct links
+    Neighbours,
+    // gaussian weights of graph distance, sigma measured in links;
+    // graph has no coordinates, so distance is number of links on shortest path
+    Gaussian(f64)
+}
+
+// normalized smoothing weights for every voxel, built once from voxel graph
+// and reused in every permutation
+#[derive(Debug, Clone)]
+pub struct SmoothingKernel {
+    weights: Vec<Vec<(usize, f64)>>
+}
+
+impl SmoothingKernel {
+    pub fn new(voxels: &Vec<Voxel>, smoothing: VarianceSmoothing) -> SmoothingKernel {
+        match smoothing {
+            VarianceSmoothing::Neighbours => SmoothingKernel::neighbours(voxels),
+            VarianceSmoothing::Gaussian(sigma) => SmoothingKernel::gaussian(voxels, sigma)
+        }
+    }
+
+    pub fn neighbours(voxels: &Vec<Voxel>) -> SmoothingKernel {
+        let weights = voxels.iter().enumerate().map(|(i, voxel)| {
+            let w = 1.0 / ((voxel.voxel_links.len() + 1) as f64);
+            let mut row = Vec::with_capacity(voxel.voxel_links.len() + 1);
+            row.push((i, w));
+            for &link in voxel.voxel_links.iter() {
+                row.push((link, w));
+            }
+            row
+        }).collect();
+        SmoothingKernel { weights }
+    }
+
+    // weights are truncated at 3 sigma
+    pub fn gaussian(voxels: &Vec<Voxel>, sigma: f64) -> SmoothingKernel {
+        assert!(sigma > 0.0, "gaussian smoothing sigma must be positive");
+        let max_distance = (3.0 * sigma).ceil() as usize;
+
+        let mut distance = vec![usize::max_value(); voxels.len()];
+        let mut queue = VecDeque::new();
+        let mut weights = Vec::with_capacity(voxels.len());
+        for i in 0..voxels.len() {
+            let mut reached = Vec::new();
+            distance[i] = 0;
+            queue.push_back(i);
+            while let Some(v) = queue.pop_front() {
+                reached.push(v);
+                if distance[v] < max_distance {
+                    for &link in voxels[v].voxel_links.iter() {
+                        if distance[link] == usize::max_value() {
+                            distance[link] = distance[v] + 1;
+                            queue.push_back(link);
+                        }
+                    }
+                }
+            }
+
+            let mut row = reached.iter()
+                .map(|&v| (v, (-((distance[v] * distance[v]) as f64) / (2.0 * sigma * sigma)).exp()))
+                .collect::<Vec<(usize, f64)>>();
+            let total = row.iter().map(|&(_, w)| w).sum::<f64>();
+            for entry in row.iter_mut() {
+                entry.1 /= total;
+            }
+            weights.push(row);
+
+            for &v in reached.iter() {
+                distance[v] = usize::max_value();
+            }
+        }
+        SmoothingKernel { weights }
+    }
+
+    pub fn smooth(&self, values: &Vec<f64>) -> Vec<f64> {
+        self.weights.iter()
+            .map(|row| row.iter().map(|&(v, w)| values[v] * w).sum())
+            .collect()
+    }
+}
+
+// paired t-test with variance smoothed by kernel
+#[derive(Debug, Clone)]
+pub struct SmoothedTTest {
+    pub kernel: SmoothingKernel
+}
+
+impl Statistic for SmoothedTTest {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        ttest_rel_smoothed_vec(&groups[0], &groups[1], &self.kernel)
+    }
+}
+
+// paired t-test with variance of differences smoothed over voxel graph
+pub fn ttest_rel_smoothed_vec(a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>, kernel: &SmoothingKernel) -> Vec<f64> {
+    let nsubj = a.len();
+    let n = a[0].len();
+    let ns = nsubj as f64;
+
+    let mut mean = Vec::with_capacity(n);
+    let mut var = Vec::with_capacity(n);
+    let mut i = 0;
+    while i < n {
+        let mut sum = 0.0;
+        let mut sum2 = 0.0;
+        let mut s = 0;
+        while s < nsubj {
+            let v = unsafe { a.get_unchecked(s).get_unchecked(i) - b.get_unchecked(s).get_unchecked(i) };
+            sum += v;
+            sum2 += v*v;
+            s += 1;
+        }
+        mean.push(sum / ns);
+        var.push((sum2 - sum*sum/ns) / (ns - 1.0));
+        i += 1;
+    }
+
+    let var = kernel.smooth(&var);
+    mean.into_iter().zip(var.into_iter()).map(|(m, v)| {
+        let t_value = m / (v / ns).sqrt();
+        if t_value.is_nan() { 0.0 } else { t_value }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::field::generate_1d_field;
+    use ::ttest::ttest_rel_vec;
+
+    #[test]
+    fn test_neighbours_kernel() {
+        let voxels = generate_1d_field(4);
+        let kernel = SmoothingKernel::neighbours(&voxels);
+        let smoothed = kernel.smooth(&vec![3.0, 0.0, 6.0, 0.0]);
+        assert_eq!(smoothed, vec![1.5, 3.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_gaussian_kernel() {
+        let voxels = generate_1d_field(20);
+        let kernel = SmoothingKernel::gaussian(&voxels, 1.0);
+        let mut values = vec![0.0; 20];
+        values[10] = 1.0;
+        let smoothed = kernel.smooth(&values);
+        assert!(smoothed[10] > smoothed[9] && smoothed[9] > smoothed[8] && smoothed[8] > smoothed[7]);
+        assert!((smoothed[9] - smoothed[11]).abs() < 1e-12);
+        // truncated at 3 sigma
+        assert_eq!(smoothed[6], 0.0);
+        // constant values are preserved
+        assert!(kernel.smooth(&vec![2.0; 20]).iter().all(|v| (v - 2.0).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_smoothed_ttest() {
+        let a = vec![vec![1.0, 2.0, 0.5], vec![2.0, 2.5, 1.0], vec![0.5, 3.0, 1.5]];
+        let b = vec![vec![0.0; 3]; 3];
+        let a = a.iter().collect();
+        let b = b.iter().collect();
+
+        // with no links smoothing has no effect
+        let isolated = vec![Voxel::new(0.0, Vec::new()); 3];
+        let t = ttest_rel_smoothed_vec(&a, &b, &SmoothingKernel::neighbours(&isolated));
+        let expected = ttest_rel_vec(&a, &b);
+        for (x, y) in t.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-10);
+        }
+
+        // voxel 1 has smallest variance, smoothing increases it
+        let t = ttest_rel_smoothed_vec(&a, &b, &SmoothingKernel::neighbours(&generate_1d_field(3)));
+        assert!(t[1] < expected[1]);
+    }
+}
@@ -0,0 +1,14 @@
+// per-voxel statistic map computed from data after permutation. Data is passed as
+// groups of observations, e.g. [condition A, condition B] of paired test,
+// [group A, group B] of independent test or conditions of every subject
+// in repeated-measures designs
+pub trait Statistic {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64>;
+}
+
+// any closure of groups can be used as statistic
+impl<F> Statistic for F where F: Fn(&Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        self(groups)
+    }
+}
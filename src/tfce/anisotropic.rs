@@ -0,0 +1,215 @@
+use ::voxel::{Voxel, Dimension};
+use super::approximate_tfce::{Discretisation, get_clusters};
+
+// extent term of one dimension: (scale * extent)^e
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DimensionExtent {
+    pub dimension: Dimension,
+    pub e: f64,
+    pub scale: f64
+}
+
+// extent of cluster along dimension: cluster size divided by number of parts
+// connected by links of that dimension only, e.g. number of time points for
+// time and number of vertices for space of space-time box cluster. Spatial
+// extent sums voxel weights, so that it is area of every time slice
+pub fn dimension_extent(voxels: &Vec<Voxel>, cluster: &Vec<usize>, dimension: Dimension) -> f64 {
+    let mut buffers = ExtentBuffers::new(voxels.len());
+    buffers.set_cluster(cluster);
+    let extent = buffers.dimension_extent(voxels, cluster, dimension);
+    buffers.clear_cluster(cluster);
+    extent
+}
+
+const NO_POSITION: usize = ::std::usize::MAX;
+
+// union-find over positions in cluster, reused by every cluster and height
+struct ExtentBuffers {
+    // position in current cluster of every voxel, NO_POSITION outside of it
+    position: Vec<usize>,
+    parent: Vec<usize>
+}
+
+impl ExtentBuffers {
+    fn new(voxel_count: usize) -> ExtentBuffers {
+        ExtentBuffers { position: vec![NO_POSITION; voxel_count], parent: Vec::new() }
+    }
+
+    fn set_cluster(&mut self, cluster: &Vec<usize>) {
+        for (p, &i) in cluster.iter().enumerate() {
+            self.position[i] = p;
+        }
+    }
+
+    fn clear_cluster(&mut self, cluster: &Vec<usize>) {
+        for &i in cluster.iter() {
+            self.position[i] = NO_POSITION;
+        }
+    }
+
+    // cluster must be set
+    fn dimension_extent(&mut self, voxels: &Vec<Voxel>, cluster: &Vec<usize>, dimension: Dimension) -> f64 {
+        self.parent.clear();
+        self.parent.extend(0..cluster.len());
+        for (p, &i) in cluster.iter().enumerate() {
+            for &ni in voxels[i].voxel_links.iter() {
+                let np = self.position[ni];
+                if np == NO_POSITION || voxels[i].link_dimension(ni) != dimension {
+                    continue;
+                }
+                let (root, other_root) = (find_root(&mut self.parent, p), find_root(&mut self.parent, np));
+                self.parent[root] = other_root;
+            }
+        }
+        let parts = (0..cluster.len()).filter(|&p| find_root(&mut self.parent, p) == p).count();
+
+        let size =
+            if dimension == Dimension::Space {
+                cluster.iter().map(|&i| voxels[i].weight).sum()
+            } else {
+                cluster.len() as f64
+            };
+        size / (parts as f64)
+    }
+}
+
+fn find_root(parent: &mut Vec<usize>, p: usize) -> usize {
+    let mut root = p;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut p = p;
+    while parent[p] != root {
+        let next = parent[p];
+        parent[p] = root;
+        p = next;
+    }
+    root
+}
+
+// discretised tfce with extent term product of dimension extent terms;
+// extent along dimension is not additive, so clusters are found at every height
+pub fn anisotropic_tfce(
+    voxels: &mut Vec<Voxel>,
+    extents: &Vec<DimensionExtent>,
+    h: f64,
+    h0: f64,
+    discretisation: Discretisation
+) {
+    for v in voxels.iter_mut() {
+        v.tfce_value = 0.0;
+    }
+
+    let max_value = voxels.iter().map(|v| v.value).fold(0.0, f64::max);
+    let delta =
+        match discretisation {
+            Discretisation::Steps(steps) => {
+                assert!(steps > 0, "step count must be positive");
+                (max_value - h0) / (steps as f64)
+            },
+            Discretisation::Dh(dh) => {
+                assert!(dh > 0.0, "dh must be positive");
+                dh
+            }
+        };
+    if !(delta > 0.0) {
+        return;
+    }
+
+    let mut buffers = ExtentBuffers::new(voxels.len());
+    let mut t = h0;
+    while t < max_value {
+        for cluster in get_clusters(voxels, t).into_iter() {
+            buffers.set_cluster(&cluster);
+            let extent_term = extents.iter()
+                .map(|de| (de.scale * buffers.dimension_extent(voxels, &cluster, de.dimension)).powf(de.e))
+                .product::<f64>();
+            buffers.clear_cluster(&cluster);
+            let increase = extent_term * (t + delta / 2.0).powf(h) * delta;
+            for i in cluster.into_iter() {
+                voxels[i].tfce_value += increase;
+            }
+        }
+        t += delta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::field::{generate_1d_field, set_random_values};
+    use ::freesurfer::{extend_graph, extend_graph_into_time};
+    use super::super::approximate_tfce::approximate_tfce;
+
+    #[test]
+    fn test_time_links_are_labelled() {
+        let voxels = extend_graph_into_time(generate_1d_field(3), 2);
+        // voxel 1 at time 0: space links to 0 and 2, time link to voxel 1 at time 1
+        assert_eq!(voxels[1].voxel_links, vec![0, 2, 4]);
+        assert_eq!(
+            voxels[1].voxel_links.iter().map(|&ni| voxels[1].link_dimension(ni)).collect::<Vec<Dimension>>(),
+            vec![Dimension::Space, Dimension::Space, Dimension::Time]
+        );
+        assert_eq!(voxels[4].voxel_links, vec![3, 5, 1]);
+        assert_eq!(voxels[4].link_dimension(1), Dimension::Time);
+    }
+
+    #[test]
+    fn test_link_labels_follow_linked_voxel() {
+        let mut voxels = extend_graph(extend_graph_into_time(generate_1d_field(3), 2), 2, Dimension::Frequency);
+        // voxel 1 at time 0 and frequency 1: time link to voxel 10, frequency link back to voxel 1
+        assert_eq!(voxels[7].voxel_links, vec![6, 8, 10, 1]);
+        assert_eq!(voxels[7].link_dimension(10), Dimension::Time);
+        assert_eq!(voxels[7].link_dimension(1), Dimension::Frequency);
+
+        // link added without label is spatial and leaves other labels in place
+        voxels[7].voxel_links.insert(0, 9);
+        assert_eq!(voxels[7].link_dimension(9), Dimension::Space);
+        assert_eq!(voxels[7].link_dimension(10), Dimension::Time);
+    }
+
+    #[test]
+    fn test_dimension_extent() {
+        let mut voxels = extend_graph_into_time(generate_1d_field(5), 4);
+        // box of vertices 1..4 at times 0..3
+        let cluster = (0..3).flat_map(|t| (1..5).map(move |n| t * 5 + n)).collect::<Vec<usize>>();
+        assert_eq!(dimension_extent(&voxels, &cluster, Dimension::Space), 4.0);
+        assert_eq!(dimension_extent(&voxels, &cluster, Dimension::Time), 3.0);
+        assert_eq!(dimension_extent(&voxels, &cluster, Dimension::Frequency), 1.0);
+
+        // spatial extent is area of time slice
+        for v in voxels.iter_mut() {
+            v.weight = 0.5;
+        }
+        assert_eq!(dimension_extent(&voxels, &cluster, Dimension::Space), 2.0);
+        assert_eq!(dimension_extent(&voxels, &cluster, Dimension::Time), 3.0);
+    }
+
+    #[test]
+    fn test_anisotropic_tfce() {
+        let mut voxels = generate_1d_field(20);
+        set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, 29830]);
+
+        // graph with space links only is isotropic tfce
+        let space = vec![DimensionExtent { dimension: Dimension::Space, e: 2.0/3.0, scale: 1.0 }];
+        let mut anisotropic_voxels = voxels.clone();
+        anisotropic_tfce(&mut anisotropic_voxels, &space, 2.0, 0.0, Discretisation::Steps(50));
+        approximate_tfce(&mut voxels, 2.0/3.0, 2.0, 0.0, Discretisation::Steps(50));
+        for (a, v) in anisotropic_voxels.iter().zip(voxels.iter()) {
+            assert!((a.tfce_value - v.tfce_value).abs() < 1e-10);
+        }
+
+        // constant box: 2 vertices and 3 time points, extent term 2^0.5 * (2 * 3)^1.5
+        let mut voxels = extend_graph_into_time(generate_1d_field(2), 3);
+        for v in voxels.iter_mut() {
+            v.value = 1.0;
+        }
+        let extents = vec![
+            DimensionExtent { dimension: Dimension::Space, e: 0.5, scale: 1.0 },
+            DimensionExtent { dimension: Dimension::Time, e: 1.5, scale: 2.0 }
+        ];
+        anisotropic_tfce(&mut voxels, &extents, 0.0, 0.0, Discretisation::Steps(4));
+        let expected = 2.0f64.sqrt() * 6.0f64.powf(1.5);
+        assert!(voxels.iter().all(|v| (v.tfce_value - expected).abs() < 1e-10));
+    }
+}
@@ -1,19 +1,43 @@
 use ::voxel::Voxel;
 use std::collections::VecDeque;
 
-pub fn approximate_tfce(voxels: &mut Vec<Voxel>, steps: i32) {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Discretisation {
+    // fixed number of steps from h0 to max value
+    Steps(i32),
+    // fixed step height dh
+    Dh(f64)
+}
+
+// discretised tfce: sum of extent^E * height^H * dh over thresholds from h0,
+// height is taken at the middle of every step
+pub fn approximate_tfce(voxels: &mut Vec<Voxel>, e: f64, h: f64, h0: f64, discretisation: Discretisation) {
     for v in voxels.iter_mut() {
         v.tfce_value = 0.0;
     }
 
     let max_value = voxels.iter().map(|v| v.value).fold(0.0, f64::max);
-    let delta = max_value / (steps as f64);
+    let delta =
+        match discretisation {
+            Discretisation::Steps(steps) => {
+                assert!(steps > 0, "step count must be positive");
+                (max_value - h0) / (steps as f64)
+            },
+            Discretisation::Dh(dh) => {
+                assert!(dh > 0.0, "dh must be positive");
+                dh
+            }
+        };
+    if !(delta > 0.0) {
+        // nothing above h0
+        return;
+    }
 
-    let mut t = 0.0;
+    let mut t = h0;
     while t < max_value {
         let clusters = get_clusters(voxels, t);
         for cluster in clusters.into_iter() {
-            let increase = (cluster.len() as f64).powf(2.0/3.0) * (t + delta / 2.0).powf(2.0) * delta;
+            let increase = cluster_extent(voxels, &cluster).powf(e) * (t + delta / 2.0).powf(h) * delta;
             for i in cluster.into_iter() {
                 voxels[i].tfce_value += increase;
             }
@@ -22,7 +46,22 @@ pub fn approximate_tfce(voxels: &mut Vec<Voxel>, steps: i32) {
     }
 }
 
+// sum of voxel weights, number of voxels if weights are 1
+pub fn cluster_extent(voxels: &Vec<Voxel>, cluster: &Vec<usize>) -> f64 {
+    cluster.iter().map(|&i| voxels[i].weight).sum()
+}
+
+// connected clusters of voxels with value >= min_value
 pub fn get_clusters(voxels: &Vec<Voxel>, min_value: f64) -> Vec<Vec<usize>> {
+    find_clusters(voxels, &|value| value >= min_value)
+}
+
+// connected clusters of voxels with value strictly above threshold
+pub fn get_clusters_above(voxels: &Vec<Voxel>, threshold: f64) -> Vec<Vec<usize>> {
+    find_clusters(voxels, &|value| value > threshold)
+}
+
+fn find_clusters(voxels: &Vec<Voxel>, included: &Fn(f64) -> bool) -> Vec<Vec<usize>> {
     let mut visited = {
         let mut v = Vec::with_capacity(voxels.len());
         let mut i = 0;
@@ -36,7 +75,7 @@ pub fn get_clusters(voxels: &Vec<Voxel>, min_value: f64) -> Vec<Vec<usize>> {
     let mut clusters = Vec::new();
     let mut i: usize = 0;
     while i < voxels.len() {
-        if !visited[i] && voxels[i].value >= min_value {
+        if !visited[i] && included(voxels[i].value) {
             let mut current_cluster = Vec::new();
             let mut queue = VecDeque::new();
             queue.push_back(i);
@@ -44,7 +83,7 @@ pub fn get_clusters(voxels: &Vec<Voxel>, min_value: f64) -> Vec<Vec<usize>> {
                 if !visited[vi] {
                     current_cluster.push(vi);
                     for ni in voxels[vi].voxel_links.iter() {
-                        if !visited[*ni] && voxels[*ni].value >= min_value {
+                        if !visited[*ni] && included(voxels[*ni].value) {
                             queue.push_back(*ni);
                         }
                     }
@@ -64,6 +103,7 @@ mod tests {
     use ::field::generate_1d_field;
     use ::field::set_random_values;
     use test::Bencher;
+    use ::tfce::tfce;
     use super::*;
 
     #[test]
@@ -89,15 +129,40 @@ mod tests {
     fn test_approximate_tfce() {
         let mut voxels = generate_1d_field(6);
         set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, 29830]);
-        approximate_tfce(&mut voxels, 50);
+        approximate_tfce(&mut voxels, 2.0/3.0, 2.0, 0.0, Discretisation::Steps(50));
         println!("{:?}", voxels.iter().map(|v| v.value).collect::<Vec<f64>>());
         println!("{:?}", voxels.iter().map(|v| v.tfce_value).collect::<Vec<f64>>());
     }
 
+    #[test]
+    fn test_approximate_tfce_parameters() {
+        let mut voxels = generate_1d_field(20);
+        set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, 29830]);
+        for &(e, h) in [(2.0/3.0, 2.0), (1.9, 0.0), (0.5, 1.0)].iter() {
+            let mut exact_voxels = voxels.clone();
+            tfce(&mut exact_voxels, e, h);
+            for &discretisation in [Discretisation::Steps(10000), Discretisation::Dh(1e-4)].iter() {
+                let mut approx_voxels = voxels.clone();
+                approximate_tfce(&mut approx_voxels, e, h, 0.0, discretisation);
+                for (a, x) in approx_voxels.iter().zip(exact_voxels.iter()) {
+                    assert!((a.tfce_value - x.tfce_value).abs() < 1e-2 * x.tfce_value.max(1e-2));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_approximate_tfce_h0() {
+        let mut voxels = vec![Voxel::new(1.0, vec![1]), Voxel::new(3.0, vec![0])];
+        approximate_tfce(&mut voxels, 1.0, 0.0, 2.0, Discretisation::Dh(0.5));
+        // only heights from 2 to 3 count, voxel 0 is below h0
+        assert_eq!(voxels.iter().map(|v| v.tfce_value).collect::<Vec<f64>>(), vec![0.0, 1.0]);
+    }
+
     #[bench]
     fn benchmark_approximate_tfce(b: &mut Bencher) {
         let mut voxels = generate_1d_field(10000);
         set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, 29830]);
-        b.iter(|| approximate_tfce(&mut voxels, 50));
+        b.iter(|| approximate_tfce(&mut voxels, 2.0/3.0, 2.0, 0.0, Discretisation::Steps(50)));
     }
 }
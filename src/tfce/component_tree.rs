@@ -0,0 +1,567 @@
+use ::voxel::Voxel;
+use ::voxel_priority::VoxelPriority;
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use std::mem;
+use std::usize;
+use super::traverse_max;
+
+// max-tree of voxel values: every component is a connected cluster of voxels
+// above some height, components merge going down until one root per connected
+// part of voxel graph remains. Values and weights are copied at build time,
+// so that tree can be reused, e.g. for tfce with many E and H.
+// Components live in one flat arena and point to their parents by index,
+// so that even very deep trees are built, walked and dropped without recursion
+#[derive(Debug, Clone)]
+pub struct ComponentTree {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    // own voxels of every node, node after node
+    voxel_indices: Vec<usize>,
+    values: Vec<f64>,
+    weights: Vec<f64>,
+    buffers: Buffers
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    // own voxels are voxel_indices[start..end], from highest to lowest value;
+    // empty when three or more components meet at one saddle voxel
+    start: usize,
+    end: usize,
+    size: usize,
+    extent: f64,
+    // at most two components merge at every height
+    parents: [Option<usize>; 2],
+    child: Option<usize>
+}
+
+// cluster left behind when climbing other hill,
+// merged back when descent goes below low_value
+#[derive(Debug, Clone)]
+struct Hunk {
+    low_value: f64,
+    voxel_queue: BinaryHeap<VoxelPriority>,
+    node: usize
+}
+
+// working memory of build, kept between rebuilds
+#[derive(Debug, Clone, Default)]
+struct Buffers {
+    visited: Vec<bool>,
+    // node that owns voxel
+    voxel_node: Vec<usize>,
+    visit_order: Vec<usize>,
+    // lowest voxel of every node so far
+    last_voxel: Vec<usize>,
+    seeds: Vec<usize>,
+    stack: Vec<usize>,
+    hunks: Vec<Hunk>,
+    spare_queues: Vec<BinaryHeap<VoxelPriority>>,
+    counts: Vec<usize>
+}
+
+const NO_VOXEL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Component<'a> {
+    tree: &'a ComponentTree,
+    index: usize
+}
+
+impl ComponentTree {
+    // empty tree, to be filled by rebuild
+    pub fn new() -> ComponentTree {
+        ComponentTree {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            voxel_indices: Vec::new(),
+            values: Vec::new(),
+            weights: Vec::new(),
+            buffers: Buffers::default()
+        }
+    }
+
+    pub fn build(voxels: &Vec<Voxel>) -> ComponentTree {
+        let mut tree = ComponentTree::new();
+        tree.rebuild(voxels);
+        tree
+    }
+
+    // replaces tree with tree of voxels, reusing memory of earlier builds
+    pub fn rebuild(&mut self, voxels: &Vec<Voxel>) {
+        let n = voxels.len();
+        let ComponentTree {
+            ref mut nodes, ref mut roots, ref mut voxel_indices,
+            ref mut values, ref mut weights, ref mut buffers
+        } = *self;
+
+        nodes.clear();
+        roots.clear();
+        values.clear();
+        values.extend(voxels.iter().map(|v| v.value));
+        weights.clear();
+        weights.extend(voxels.iter().map(|v| v.weight));
+
+        buffers.visited.clear();
+        buffers.visited.resize(n, false);
+        buffers.voxel_node.clear();
+        buffers.voxel_node.resize(n, 0);
+        buffers.visit_order.clear();
+        buffers.last_voxel.clear();
+
+        find_seeds(voxels, buffers);
+        for s in 0..buffers.seeds.len() {
+            let seed = buffers.seeds[s];
+            let root = Builder { voxels, nodes, buffers }.build_component(seed);
+            roots.push(root);
+        }
+
+        // own voxels are grouped by node, keeping order of visits
+        let Buffers { ref visit_order, ref voxel_node, ref mut counts, .. } = *buffers;
+        counts.clear();
+        counts.resize(nodes.len() + 1, 0);
+        for &vi in visit_order.iter() {
+            counts[voxel_node[vi] + 1] += 1;
+        }
+        for i in 1..counts.len() {
+            counts[i] += counts[i - 1];
+        }
+        for (i, node) in nodes.iter_mut().enumerate() {
+            node.start = counts[i];
+            node.end = counts[i + 1];
+        }
+        voxel_indices.clear();
+        voxel_indices.resize(n, 0);
+        for &vi in visit_order.iter() {
+            let node = voxel_node[vi];
+            voxel_indices[counts[node]] = vi;
+            counts[node] += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn voxel_count(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn component<'a>(&'a self, index: usize) -> Component<'a> {
+        assert!(index < self.nodes.len(), "component index out of range");
+        Component { tree: self, index }
+    }
+
+    // one root per connected part of voxel graph
+    pub fn roots<'a>(&'a self) -> Box<Iterator<Item = Component<'a>> + 'a> {
+        Box::new(self.roots.iter().map(move |&index| Component { tree: self, index }))
+    }
+
+    // every component, parents before the component they merge into
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = Component<'a>> + 'a> {
+        Box::new((0..self.nodes.len()).map(move |index| Component { tree: self, index }))
+    }
+
+    // exact tfce of values the tree was built from, extent is sum of voxel weights
+    pub fn tfce(&self, e: f64, h: f64) -> Vec<f64> {
+        let mut tfce_values = vec![0.0; self.values.len()];
+        self.for_each_tfce(e, h, |vi, tfce_value| tfce_values[vi] = tfce_value);
+        tfce_values
+    }
+
+    // tfce map for every (E, H) pair
+    pub fn tfce_batch(&self, parameters: &[(f64, f64)]) -> Vec<Vec<f64>> {
+        parameters.iter().map(|&(e, h)| self.tfce(e, h)).collect()
+    }
+
+    // calls set with index and tfce value of every voxel
+    pub fn for_each_tfce<F: FnMut(usize, f64)>(&self, e: f64, h: f64, mut set: F) {
+        let h1 = h + 1.0;
+
+        let mut stack = self.roots.iter().map(|&index| (index, 0.0f64, 0.0f64)).collect::<Vec<(usize, f64, f64)>>();
+        while let Some((index, mut prev_value, mut prev_tfce_value)) = stack.pop() {
+            let node = &self.nodes[index];
+            let mut extent = node.extent;
+            for &vi in self.voxel_indices[node.start..node.end].iter().rev() {
+                let value = self.values[vi];
+                let tfce_value =
+                    prev_tfce_value +
+                    extent.powf(e) * ((value.powf(h1) - prev_value.powf(h1)) / h1);
+                set(vi, tfce_value);
+
+                prev_value = value;
+                prev_tfce_value = tfce_value;
+                extent -= self.weights[vi];
+            }
+            for parent in node.parents.iter().filter_map(|&p| p) {
+                stack.push((parent, prev_value, prev_tfce_value));
+            }
+        }
+    }
+}
+
+// every connected part of voxel graph is built from its max voxel,
+// parts with higher max first, ties broken by voxel index
+fn find_seeds(voxels: &Vec<Voxel>, buffers: &mut Buffers) {
+    let Buffers { ref mut visited, ref mut stack, ref mut seeds, .. } = *buffers;
+    seeds.clear();
+    for i in 0..voxels.len() {
+        if visited[i] {
+            continue;
+        }
+        let mut max_index = i;
+        visited[i] = true;
+        stack.push(i);
+        while let Some(vi) = stack.pop() {
+            let value = voxels[vi].value;
+            let max_value = voxels[max_index].value;
+            if value > max_value || (value == max_value && vi < max_index) {
+                max_index = vi;
+            }
+            for &ni in voxels[vi].voxel_links.iter() {
+                if !visited[ni] {
+                    visited[ni] = true;
+                    stack.push(ni);
+                }
+            }
+        }
+        seeds.push(max_index);
+    }
+    for v in visited.iter_mut() {
+        *v = false;
+    }
+    seeds.sort_by(|&i, &j| {
+        voxels[j].value.partial_cmp(&voxels[i].value).unwrap_or(Ordering::Equal).then(i.cmp(&j))
+    });
+}
+
+struct Builder<'a> {
+    voxels: &'a Vec<Voxel>,
+    nodes: &'a mut Vec<Node>,
+    buffers: &'a mut Buffers
+}
+
+impl<'a> Builder<'a> {
+    fn new_node(&mut self) -> usize {
+        self.nodes.push(Node { start: 0, end: 0, size: 0, extent: 0.0, parents: [None, None], child: None });
+        self.buffers.last_voxel.push(NO_VOXEL);
+        self.nodes.len() - 1
+    }
+
+    fn new_queue(&mut self) -> BinaryHeap<VoxelPriority> {
+        self.buffers.spare_queues.pop().unwrap_or_else(BinaryHeap::new)
+    }
+
+    fn visit(&mut self, index: usize, node: usize) {
+        self.buffers.visited[index] = true;
+        self.buffers.voxel_node[index] = node;
+        self.buffers.last_voxel[node] = index;
+        self.buffers.visit_order.push(index);
+        self.nodes[node].size += 1;
+        self.nodes[node].extent += self.voxels[index].weight;
+    }
+
+    // merge voxel queues, smaller into bigger
+    fn merge_queues(&mut self, voxel_queue: &mut BinaryHeap<VoxelPriority>, mut other_queue: BinaryHeap<VoxelPriority>) {
+        if voxel_queue.len() < other_queue.len() {
+            mem::swap(voxel_queue, &mut other_queue);
+        }
+        for i in other_queue.drain() {
+            voxel_queue.push(i);
+        }
+        self.buffers.spare_queues.push(other_queue);
+    }
+
+    // create new node, pointing to two older ones; lowest voxel
+    // of other node is moved into it
+    fn merge(&mut self, other: usize, current: usize) -> usize {
+        let shared_voxel = self.buffers.last_voxel[other];
+        assert!(shared_voxel != NO_VOXEL, "merged cluster has no voxels");
+        let weight = self.voxels[shared_voxel].weight;
+        self.nodes[other].size -= 1;
+        self.nodes[other].extent -= weight;
+
+        let merged = self.new_node();
+        self.buffers.voxel_node[shared_voxel] = merged;
+        self.buffers.last_voxel[merged] = shared_voxel;
+        self.nodes[merged].size = self.nodes[current].size + self.nodes[other].size + 1;
+        self.nodes[merged].extent = self.nodes[current].extent + self.nodes[other].extent + weight;
+        self.nodes[merged].parents = [Some(other), Some(current)];
+        self.nodes[other].child = Some(merged);
+        self.nodes[current].child = Some(merged);
+        merged
+    }
+
+    fn build_component(&mut self, max_voxel_index: usize) -> usize {
+        let mut voxel_queue = self.new_queue();
+
+        let max_value = self.voxels[max_voxel_index].value;
+        voxel_queue.push(VoxelPriority { value: max_value, index: max_voxel_index });
+
+        let mut current_value = max_value;
+        let mut low_value = 0.0;
+
+        let mut current_node = self.new_node();
+
+        while !self.buffers.hunks.is_empty() || !voxel_queue.is_empty() {
+            while let Some(VoxelPriority { value, index }) = voxel_queue.pop() {
+                if !self.buffers.visited[index] {
+                    if value < low_value && !self.buffers.hunks.is_empty() {
+                        let other_hunk = self.buffers.hunks.pop().unwrap();
+                        self.merge_queues(&mut voxel_queue, other_hunk.voxel_queue);
+                        current_node = self.merge(other_hunk.node, current_node);
+
+                        // push current voxel onto queue
+                        voxel_queue.push(VoxelPriority { value, index });
+
+                        // set low value to older low value
+                        current_value = low_value;
+                        low_value = other_hunk.low_value;
+                    } else if value <= current_value {
+                        // normal descent, still in the same cluster
+                        // just mark as visited, add to current node
+                        // and traverse voxel links
+                        current_value = value;
+                        self.visit(index, current_node);
+                        for &ni in self.voxels[index].voxel_links.iter() {
+                            if !self.buffers.visited[ni] {
+                                voxel_queue.push(VoxelPriority { value: self.voxels[ni].value, index: ni });
+                            }
+                        }
+                    } else {
+                        // traversed to other hill
+                        let new_queue = self.new_queue();
+                        self.buffers.hunks.push(Hunk {
+                            low_value: low_value,
+                            voxel_queue: mem::replace(&mut voxel_queue, new_queue),
+                            node: current_node
+                        });
+                        current_node = self.new_node();
+                        low_value = current_value;
+
+                        let mut max_index = index;
+                        while let Some(bigger_index) = traverse_max(max_index, self.voxels) {
+                            max_index = bigger_index;
+                        }
+
+                        current_value = self.voxels[max_index].value;
+                        voxel_queue.push(VoxelPriority { value: current_value, index: max_index });
+                    }
+                }
+            }
+
+            if let Some(other_hunk) = self.buffers.hunks.pop() {
+                self.merge_queues(&mut voxel_queue, other_hunk.voxel_queue);
+                current_node = self.merge(other_hunk.node, current_node);
+
+                // set low value to older low value
+                current_value = low_value;
+                low_value = other_hunk.low_value;
+            }
+        }
+
+        self.buffers.spare_queues.push(voxel_queue);
+        current_node
+    }
+}
+
+impl<'a> Component<'a> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    fn node(&self) -> &'a Node {
+        &self.tree.nodes[self.index]
+    }
+
+    // own voxels, not in any parent, from highest to lowest value
+    pub fn voxels(&self) -> &'a [usize] {
+        let node = self.node();
+        &self.tree.voxel_indices[node.start..node.end]
+    }
+
+    // voxel count of component with its parents
+    pub fn size(&self) -> usize {
+        self.node().size
+    }
+
+    // sum of voxel weights of component with its parents
+    pub fn extent(&self) -> f64 {
+        self.node().extent
+    }
+
+    // height where parents merge into this component, peak height if it has no parents
+    pub fn merge_height(&self) -> f64 {
+        self.tree.values[self.saddle_voxel()]
+    }
+
+    // lowest height of component, below it component merges into its child
+    pub fn lowest_height(&self) -> f64 {
+        match self.voxels().last() {
+            Some(&vi) => self.tree.values[vi],
+            None => self.merge_height()
+        }
+    }
+
+    // first own voxel, or of the nearest child for component without own
+    // voxels, whose parents merge at the same saddle where it merges down
+    fn saddle_voxel(&self) -> usize {
+        let mut index = self.index;
+        loop {
+            let node = &self.tree.nodes[index];
+            if node.start < node.end {
+                return self.tree.voxel_indices[node.start];
+            }
+            index = node.child.expect("component without voxels has no child");
+        }
+    }
+
+    // higher components merged into this one
+    pub fn parents(&self) -> Box<Iterator<Item = Component<'a>> + 'a> {
+        let tree = self.tree;
+        Box::new(self.node().parents.iter().filter_map(|&p| p).map(move |index| Component { tree, index }))
+    }
+
+    // all voxels of component with its parents
+    pub fn members(&self) -> Vec<usize> {
+        let mut members = Vec::with_capacity(self.size());
+        let mut stack = vec![self.index];
+        while let Some(index) = stack.pop() {
+            members.extend(self.tree.component(index).voxels().iter().cloned());
+            stack.extend(self.tree.nodes[index].parents.iter().filter_map(|&p| p));
+        }
+        members
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::field::{generate_1d_field, generate_2d8c_field, set_random_values};
+    use super::super::approximate_tfce::{approximate_tfce, Discretisation};
+
+    fn five_voxels() -> Vec<Voxel> {
+        vec![
+            Voxel::new(3.0, vec![1]),
+            Voxel::new(2.0, vec![0, 2]),
+            Voxel::new(1.0, vec![1, 3]),
+            Voxel::new(2.5, vec![2, 4]),
+            Voxel::new(2.0, vec![3])
+        ]
+    }
+
+    #[test]
+    fn test_component_tree() {
+        let tree = ComponentTree::build(&five_voxels());
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.voxel_count(), 5);
+
+        let roots = tree.roots().collect::<Vec<Component>>();
+        assert_eq!(roots.len(), 1);
+        let root = roots[0];
+        assert_eq!(root.voxels(), &[2]);
+        assert_eq!(root.size(), 5);
+        assert_eq!(root.extent(), 5.0);
+        assert_eq!(root.merge_height(), 1.0);
+
+        let parents = root.parents().collect::<Vec<Component>>();
+        assert_eq!(parents.iter().map(|c| c.voxels().to_vec()).collect::<Vec<Vec<usize>>>(), vec![vec![0, 1], vec![3, 4]]);
+        assert_eq!(parents[0].merge_height(), 3.0);
+        assert_eq!(parents[0].lowest_height(), 2.0);
+        assert_eq!(parents[1].size(), 2);
+        assert_eq!(parents[1].parents().count(), 0);
+
+        let mut members = root.members();
+        members.sort();
+        assert_eq!(members, vec![0, 1, 2, 3, 4]);
+        assert_eq!(tree.iter().map(|c| c.size()).sum::<usize>(), 9);
+    }
+
+    fn sorted_members(component: &Component) -> Vec<usize> {
+        let mut members = component.members();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn test_component_tree_branching() {
+        // peaks 3, 2.5, 2, 4 with saddles 1, 0.5, 1.5
+        let mut voxels = generate_1d_field(7);
+        for (v, &value) in voxels.iter_mut().zip([3.0, 1.0, 2.5, 0.5, 2.0, 1.5, 4.0].iter()) {
+            v.value = value;
+        }
+        let tree = ComponentTree::build(&voxels);
+        assert_eq!(tree.len(), 7);
+
+        let root = tree.roots().next().unwrap();
+        assert_eq!(root.voxels(), &[3]);
+        assert_eq!((root.merge_height(), root.lowest_height()), (0.5, 0.5));
+        assert_eq!(sorted_members(&root), vec![0, 1, 2, 3, 4, 5, 6]);
+
+        let parents = root.parents().collect::<Vec<Component>>();
+        assert_eq!(parents.len(), 2);
+        let (right, left) = (parents[0], parents[1]);
+        assert_eq!(sorted_members(&right), vec![4, 5, 6]);
+        assert_eq!(sorted_members(&left), vec![0, 1, 2]);
+        assert_eq!((right.merge_height(), right.lowest_height(), right.size()), (1.5, 1.5, 3));
+        assert_eq!((left.merge_height(), left.lowest_height(), left.size()), (1.0, 1.0, 3));
+
+        let peaks = right.parents().chain(left.parents()).collect::<Vec<Component>>();
+        assert_eq!(peaks.iter().map(|c| c.voxels().to_vec()).collect::<Vec<Vec<usize>>>(), vec![vec![6], vec![4], vec![2], vec![0]]);
+        assert_eq!(peaks.iter().map(|c| c.merge_height()).collect::<Vec<f64>>(), vec![4.0, 2.0, 2.5, 3.0]);
+        assert!(peaks.iter().all(|c| c.parents().count() == 0 && c.members() == c.voxels().to_vec()));
+    }
+
+    #[test]
+    fn test_component_tree_three_way_saddle() {
+        // star: leaves 3, 2 and 2.5 meet at center 1, so one merged
+        // component is left without voxels of its own
+        let voxels = vec![
+            Voxel::new(1.0, vec![1, 2, 3]),
+            Voxel::new(3.0, vec![0]),
+            Voxel::new(2.0, vec![0]),
+            Voxel::new(2.5, vec![0])
+        ];
+        let tree = ComponentTree::build(&voxels);
+        let empty = tree.iter().filter(|c| c.voxels().is_empty()).collect::<Vec<Component>>();
+        assert_eq!(empty.len(), 1);
+        assert_eq!((empty[0].merge_height(), empty[0].lowest_height()), (1.0, 1.0));
+        assert_eq!(sorted_members(&empty[0]), vec![1, 3]);
+
+        let mut heights = tree.iter().map(|c| (c.merge_height(), c.lowest_height())).collect::<Vec<(f64, f64)>>();
+        heights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(heights, vec![(1.0, 1.0), (1.0, 1.0), (2.0, 2.0), (2.5, 2.5), (3.0, 3.0)]);
+
+        // E = 1, H = 0: integral of cluster size, 4 up to 1 and 1 above
+        assert_eq!(tree.tfce(1.0, 0.0), vec![4.0, 6.0, 5.0, 5.5]);
+    }
+
+    #[test]
+    fn test_component_tree_tfce() {
+        let mut voxels = generate_2d8c_field(8);
+        set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, 29830]);
+        let tree = ComponentTree::build(&voxels);
+        for &(e, h) in [(2.0/3.0, 2.0), (1.0, 2.0), (0.5, 1.0)].iter() {
+            let mut approx_voxels = voxels.clone();
+            approximate_tfce(&mut approx_voxels, e, h, 0.0, Discretisation::Steps(10000));
+            for (tv, a) in tree.tfce(e, h).iter().zip(approx_voxels.iter()) {
+                assert!((tv - a.tfce_value).abs() < 1e-3 * tv.max(1.0));
+            }
+        }
+        // building tree does not change voxels
+        assert!(voxels.iter().all(|v| v.tfce_value == 0.0));
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut voxels = generate_2d8c_field(8);
+        set_random_values(&mut voxels, 0.0, 1.0, &[17556, 31771, 29830, 29830]);
+        let mut tree = ComponentTree::build(&five_voxels());
+        tree.rebuild(&voxels);
+        assert_eq!(tree.tfce(2.0/3.0, 2.0), ComponentTree::build(&voxels).tfce(2.0/3.0, 2.0));
+        tree.rebuild(&five_voxels());
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.voxel_count(), 5);
+    }
+}
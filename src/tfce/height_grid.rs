@@ -0,0 +1,173 @@
+use ::voxel::Voxel;
+use super::approximate_tfce::{get_clusters, get_clusters_above, cluster_extent};
+
+// discretised tfce on fixed height grids instead of exact integral; grids are
+// modelled on loops of other tfce implementations, but no output is checked
+// against them, so names only describe the grid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeightGrid {
+    // heights h0 + dh, h0 + 2 dh, ... up to max value inclusive, accumulated in
+    // single precision, clusters of voxels >= height, every height adds
+    // extent^E * height^H * dh; dh defaults to max value / 100
+    ScaledSteps { h0: f64, dh: Option<f64> },
+    // heights start, start + step, ... below max value (numpy.arange), clusters
+    // of voxels > height, every height adds extent^E * height^H, not scaled by step
+    UnscaledSteps { start: f64, step: f64 }
+}
+
+pub fn height_grid_tfce(voxels: &mut Vec<Voxel>, e: f64, h: f64, grid: HeightGrid) {
+    match grid {
+        HeightGrid::ScaledSteps { h0, dh } => scaled_steps_tfce(voxels, e, h, h0, dh),
+        HeightGrid::UnscaledSteps { start, step } => unscaled_steps_tfce(voxels, e, h, start, step)
+    }
+}
+
+pub fn scaled_steps_tfce(voxels: &mut Vec<Voxel>, e: f64, h: f64, h0: f64, dh: Option<f64>) {
+    // voxel values, heights and step are single precision, so accumulated
+    // rounding decides whether the step at max value is included
+    let values = voxels.iter().map(|v| v.value).collect::<Vec<f64>>();
+    for v in voxels.iter_mut() {
+        v.value = v.value as f32 as f64;
+    }
+    let max_value = voxels.iter().map(|v| v.value as f32).fold(0.0, f32::max);
+    let dh = dh.map_or(max_value / 100.0, |dh| dh as f32);
+    let mut heights = Vec::new();
+    if dh > 0.0 {
+        let mut height = h0 as f32 + dh;
+        while height <= max_value {
+            heights.push(height as f64);
+            height += dh;
+        }
+    }
+    add_height_scores(voxels, e, h, &heights, dh as f64, true);
+    for (v, value) in voxels.iter_mut().zip(values.into_iter()) {
+        v.value = value;
+    }
+}
+
+pub fn unscaled_steps_tfce(voxels: &mut Vec<Voxel>, e: f64, h: f64, start: f64, step: f64) {
+    assert!(step > 0.0, "tfce height step must be positive");
+    let max_value = voxels.iter().map(|v| v.value).fold(0.0, f64::max);
+    // same length as numpy.arange(start, max_value, step)
+    let count = ((max_value - start) / step).ceil().max(0.0) as usize;
+    let heights = (0..count).map(|i| start + (i as f64) * step).collect::<Vec<f64>>();
+    add_height_scores(voxels, e, h, &heights, 1.0, false);
+}
+
+fn add_height_scores(voxels: &mut Vec<Voxel>, e: f64, h: f64, heights: &Vec<f64>, scale: f64, inclusive: bool) {
+    for v in voxels.iter_mut() {
+        v.tfce_value = 0.0;
+    }
+    for &height in heights.iter() {
+        let clusters =
+            if inclusive {
+                get_clusters(voxels, height)
+            } else {
+                get_clusters_above(voxels, height)
+            };
+        for cluster in clusters.into_iter() {
+            let increase = cluster_extent(voxels, &cluster).powf(e) * height.powf(h);
+            for i in cluster.into_iter() {
+                voxels[i].tfce_value += increase;
+            }
+        }
+    }
+    for v in voxels.iter_mut() {
+        v.tfce_value *= scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::field::generate_1d_field;
+
+    fn voxels(values: &[f64]) -> Vec<Voxel> {
+        let mut voxels = generate_1d_field(values.len());
+        for (v, &value) in voxels.iter_mut().zip(values.iter()) {
+            v.value = value;
+        }
+        voxels
+    }
+
+    fn tfce_values(voxels: &Vec<Voxel>) -> Vec<f64> {
+        voxels.iter().map(|v| v.tfce_value).collect()
+    }
+
+    fn assert_close(actual: Vec<f64>, expected: Vec<f64>) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, x) in actual.iter().zip(expected.iter()) {
+            assert!((a - x).abs() < 1e-9 * x.abs().max(1.0), "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    // expected values are worked out by hand from height grids described above
+
+    #[test]
+    fn test_scaled_steps() {
+        // heights 1, 2, 3, 4: clusters {1, 2, 3}, {1, 2, 3}, {2}, {2}
+        let mut v = voxels(&[0.0, 2.0, 4.0, 2.0, 0.0]);
+        scaled_steps_tfce(&mut v, 1.0, 1.0, 0.0, Some(1.0));
+        assert_close(tfce_values(&v), vec![0.0, 9.0, 16.0, 9.0, 0.0]);
+
+        // default dh = 4 / 100, E = 0.5, H = 2:
+        // voxels 1, 3: 0.04^3 * sqrt(3) * sum(i^2, i = 1..50)
+        // voxel 2: that plus 0.04^3 * sum(i^2, i = 51..100),
+        // up to single precision rounding of heights
+        let mut v = voxels(&[0.0, 2.0, 4.0, 2.0, 0.0]);
+        scaled_steps_tfce(&mut v, 0.5, 2.0, 0.0, None);
+        let low = 0.000064 * 3.0f64.sqrt() * 42925.0;
+        let expected = vec![0.0, low, low + 0.000064 * 295425.0, low, 0.0];
+        for (a, x) in tfce_values(&v).iter().zip(expected.iter()) {
+            assert!((a - x).abs() < 1e-5 * x.max(1.0), "{} != {}", a, x);
+        }
+
+        // h0 = 1.5, dh = 1: heights 2.5, 3.5
+        let mut v = voxels(&[0.0, 2.0, 4.0, 2.0, 0.0]);
+        scaled_steps_tfce(&mut v, 1.0, 1.0, 1.5, Some(1.0));
+        assert_close(tfce_values(&v), vec![0.0, 0.0, 6.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_scaled_steps_single_precision() {
+        // 0.1 accumulated ten times in single precision is above 1, so the
+        // last height is dropped, while 0.125 adds up exactly
+        let mut v = voxels(&[0.0, 1.0, 0.0]);
+        scaled_steps_tfce(&mut v, 1.0, 0.0, 0.0, Some(0.1));
+        assert_close(tfce_values(&v), vec![0.0, 9.0 * (0.1f32 as f64), 0.0]);
+        let mut v = voxels(&[0.0, 1.0, 0.0]);
+        scaled_steps_tfce(&mut v, 1.0, 0.0, 0.0, Some(0.125));
+        assert_close(tfce_values(&v), vec![0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_unscaled_steps() {
+        // heights 0, 1, 2, 3: clusters {1, 2, 3}, {1, 2, 3}, {2}, {2}
+        let mut v = voxels(&[0.0, 2.0, 4.0, 2.0, 0.0]);
+        unscaled_steps_tfce(&mut v, 1.0, 1.0, 0.0, 1.0);
+        assert_close(tfce_values(&v), vec![0.0, 3.0, 8.0, 3.0, 0.0]);
+
+        // E = 0.5, H = 2, start 0.5, step 0.5:
+        // heights 0.5, 1, 1.5 with extent 3, heights 2, 2.5, 3, 3.5 with extent 1
+        let mut v = voxels(&[0.0, 2.0, 4.0, 2.0, 0.0]);
+        unscaled_steps_tfce(&mut v, 0.5, 2.0, 0.5, 0.5);
+        let low = 3.0f64.sqrt() * 3.5;
+        assert_close(tfce_values(&v), vec![0.0, low, low + 31.5, low, 0.0]);
+    }
+
+    #[test]
+    fn test_height_grids_approach_exact() {
+        let values = [0.3, 1.2, 2.7, 1.9, 0.1, 2.2, 2.4, 0.0];
+        let mut exact = voxels(&values);
+        ::tfce::tfce(&mut exact, 0.5, 2.0);
+
+        let mut scaled = voxels(&values);
+        scaled_steps_tfce(&mut scaled, 0.5, 2.0, 0.0, Some(1e-4));
+        let mut unscaled = voxels(&values);
+        unscaled_steps_tfce(&mut unscaled, 0.5, 2.0, 0.0, 1e-4);
+        for ((x, f), m) in tfce_values(&exact).iter().zip(tfce_values(&scaled).iter()).zip(tfce_values(&unscaled).iter()) {
+            assert!((f - x).abs() < 1e-3 * x.max(1.0));
+            assert!((m * 1e-4 - x).abs() < 1e-3 * x.max(1.0));
+        }
+    }
+}
@@ -1,230 +1,178 @@
 pub mod approximate_tfce;
 pub use self::approximate_tfce::approximate_tfce;
+pub mod height_grid;
+pub mod anisotropic;
+pub mod component_tree;
+pub use self::component_tree::{ComponentTree, Component};
 #[cfg(test)] mod tfce_tests;
 
-use std::collections::BinaryHeap;
-use std::mem;
+use std::cell::RefCell;
 use ::voxel::Voxel;
-use ::voxel_priority::VoxelPriority;
 use ::permutation;
-
-#[derive(Debug, PartialEq, Eq)]
-struct Cluster {
-    voxel_indices: Vec<usize>,
-    size: usize,
-    parent_cluster_1: Option<Box<Cluster>>,
-    parent_cluster_2: Option<Box<Cluster>>
-}
-impl Cluster {
-    fn new() -> Cluster {
-        Cluster {
-            voxel_indices: Vec::new(),
-            size: 0,
-            parent_cluster_1: None,
-            parent_cluster_2: None
-        }
-    }
-}
-
-#[derive(Debug)]
-struct ClusterHunk {
-    low_value: f64,
-    voxel_queue: BinaryHeap<VoxelPriority>,
-    cluster: Cluster
+use ::permutation::{Tail, Permutations, PermutationScheme, SignFlips};
+use ::ttest::OneSampleTTest;
+use ::statistic::Statistic;
+use ::glm::Glm;
+use ::anova::RmAnova;
+use ::enhancement::Enhancement;
+
+thread_local! {
+    // cluster tree and its buffers are reused by every tfce of thread
+    static COMPONENT_TREE: RefCell<ComponentTree> = RefCell::new(ComponentTree::new());
 }
 
 pub fn tfce(voxels: &mut Vec<Voxel>, e: f64, h: f64) {
-    let cluster = build_cluster_tree(voxels);
-    fill_clusters(voxels, cluster, e, h);
+    COMPONENT_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.rebuild(voxels);
+        tree.for_each_tfce(e, h, |vi, tfce_value| voxels[vi].tfce_value = tfce_value);
+    });
 }
 
-pub fn run_permutation(
-    mut voxels: &mut Vec<Voxel>,
-    a: &Vec<Vec<f64>>,
-    b: &Vec<Vec<f64>>,
-    n: i32,
-    e: f64,
-    h: f64,
-    negative: bool,
-    positive: bool
-) -> Vec<bool> {
-    permutation::run_permutation(
-        &a, &b, n,
-        &mut |a, b| {
-            for (v, tv) in voxels.iter_mut().zip(::ttest::ttest_rel_vec(&a, &b).into_iter()) {
-                if negative {
-                    v.value = tv.min(0.0).abs();
-                } else if positive {
-                    v.value = tv.max(0.0);
-                } else {
-                    v.value = tv.abs();
-                }
-            }
-            tfce(&mut voxels, e, h);
-            voxels.iter().map(|v| v.tfce_value).collect()
-        }
-    )
+pub fn signed_tfce(voxels: &mut Vec<Voxel>, tail: Tail, e: f64, h: f64) {
+    signed_enhancement(voxels, tail, &mut |voxels| tfce(voxels, e, h));
 }
 
+// enhance positive and negative parts separately, so that touching
+// positive and negative effects don't end up in the same cluster;
+// enhance must set tfce_value of every voxel from non-negative values
+pub fn signed_enhancement(voxels: &mut Vec<Voxel>, tail: Tail, enhance: &mut FnMut(&mut Vec<Voxel>)) {
+    let values = voxels.iter().map(|v| v.value).collect::<Vec<f64>>();
+    let mut tfce_values = vec![0.0; voxels.len()];
 
-fn build_cluster_tree(voxels: &mut Vec<Voxel>) -> Cluster {
-    let mut visited = {
-        let mut v = Vec::with_capacity(voxels.len());
-        let mut i = 0;
-        while i < voxels.len() {
-            v.push(false);
-            i += 1;
+    if tail.includes_positive() {
+        for (v, &value) in voxels.iter_mut().zip(values.iter()) {
+            v.value = value.max(0.0);
         }
-        v
-    };
-
-    let mut voxel_queue = BinaryHeap::new();
-
-    let max_voxel_index = find_max_voxel(voxels);
-    let max_value = voxels[max_voxel_index].value;
-    voxel_queue.push(VoxelPriority { value: max_value, index: max_voxel_index });
-
-    let mut cluster_stack: Vec<ClusterHunk> = Vec::new();
-
-    let mut current_value = max_value;
-    let mut low_value = 0.0;
-
-    let mut current_cluster = Cluster::new();
-
-    while !cluster_stack.is_empty() || !voxel_queue.is_empty() {
-        while let Some(VoxelPriority { value, index }) = voxel_queue.pop() {
-            if !visited[index] {
-                if value < low_value && !cluster_stack.is_empty() {
-                    let mut other_hunk = cluster_stack.pop().unwrap();
-
-                    // merge voxel queues, smaller into bigger
-                    if voxel_queue.len() < other_hunk.voxel_queue.len() {
-                        mem::swap(&mut voxel_queue, &mut other_hunk.voxel_queue);
-                    }
-                    for i in other_hunk.voxel_queue.into_iter() {
-                        voxel_queue.push(i);
-                    }
-
-                    let shared_voxel = other_hunk.cluster.voxel_indices.pop().unwrap();
-                    other_hunk.cluster.size -= 1;
-
-                    // create new cluster, pointing to two older ones
-                    current_cluster = Cluster {
-                        voxel_indices: vec![shared_voxel],
-                        size: current_cluster.size + other_hunk.cluster.size + 1,
-                        parent_cluster_1: Some(Box::new(other_hunk.cluster)),
-                        parent_cluster_2: Some(Box::new(current_cluster))
-                    };
-
-                    // push current voxel onto queue
-                    voxel_queue.push(VoxelPriority { value, index });
-
-                    // set low value to older low value
-                    current_value = low_value;
-                    low_value = other_hunk.low_value;
-                } else if value <= current_value {
-                    // normal descent, still in the same cluster
-                    // just mark as visited, add to current cluster
-                    // and traverse voxel links
-                    current_value = value;
-                    visited[index] = true;
-                    current_cluster.voxel_indices.push(index);
-                    current_cluster.size += 1;
-                    for &ni in voxels[index].voxel_links.iter() {
-                        if !visited[ni] {
-                            voxel_queue.push(VoxelPriority { value: voxels[ni].value, index: ni });
-                        }
-                    }
-                } else {
-                    // traversed to other hill
-                    cluster_stack.push(ClusterHunk {
-                        low_value: low_value,
-                        voxel_queue: mem::replace(&mut voxel_queue, BinaryHeap::new()),
-                        cluster: mem::replace(&mut current_cluster, Cluster::new())
-                    });
-                    low_value = current_value;
-
-                    let mut max_index = index;
-                    while let Some(bigger_index) = traverse_max(max_index, voxels) {
-                        max_index = bigger_index;
-                    }
-
-                    current_value = voxels[max_index].value;
-                    voxel_queue.push(VoxelPriority { value: current_value, index: max_index });
-                }
-            }
+        enhance(voxels);
+        for (tv, v) in tfce_values.iter_mut().zip(voxels.iter()) {
+            *tv += v.tfce_value;
         }
-
-        if let Some(mut other_hunk) = cluster_stack.pop() {
-            // merge voxel queues, smaller into bigger
-            if voxel_queue.len() < other_hunk.voxel_queue.len() {
-                mem::swap(&mut voxel_queue, &mut other_hunk.voxel_queue);
-            }
-            for i in other_hunk.voxel_queue.into_iter() {
-                voxel_queue.push(i);
-            }
-
-            let shared_voxel = other_hunk.cluster.voxel_indices.pop().unwrap();
-            other_hunk.cluster.size -= 1;
-
-            // create new cluster, pointing to two older ones
-            current_cluster = Cluster {
-                voxel_indices: vec![shared_voxel],
-                size: current_cluster.size + other_hunk.cluster.size + 1,
-                parent_cluster_1: Some(Box::new(other_hunk.cluster)),
-                parent_cluster_2: Some(Box::new(current_cluster))
-            };
-
-            // set low value to older low value
-            current_value = low_value;
-            low_value = other_hunk.low_value;
+    }
+    if tail.includes_negative() {
+        for (v, &value) in voxels.iter_mut().zip(values.iter()) {
+            v.value = (-value).max(0.0);
+        }
+        enhance(voxels);
+        for (tv, v) in tfce_values.iter_mut().zip(voxels.iter()) {
+            *tv -= v.tfce_value;
         }
     }
 
-    current_cluster
+    for ((v, value), tv) in voxels.iter_mut().zip(values.into_iter()).zip(tfce_values.into_iter()) {
+        v.value = value;
+        v.tfce_value = tv;
+    }
 }
 
-fn fill_clusters(voxels: &mut Vec<Voxel>, root_cluster: Cluster, e: f64, h: f64) {
-    let mut cluster_stack = Vec::new();
-    cluster_stack.push((root_cluster, 0.0f64, 0.0f64));
-
-    while let Some((cluster, mut prev_value, mut prev_tfce_value)) = cluster_stack.pop() {
-        let mut sz = cluster.size;
-        for vi in cluster.voxel_indices.into_iter().rev() {
-            let value = voxels[vi].value;
-            let h1 = h + 1.0;
-            let tfce_value =
-                prev_tfce_value +
-                (sz as f64).powf(e) * ((value.powf(h1) - prev_value.powf(h1)) / h1);
-            voxels[vi].tfce_value = tfce_value;
+// replaces voxel values with statistic map and returns its signed tfce
+pub fn enhance_statistic(voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail, e: f64, h: f64) -> Vec<f64> {
+    for (v, sv) in voxels.iter_mut().zip(statistic.into_iter()) {
+        v.value = sv;
+    }
+    signed_tfce(voxels, tail, e, h);
+    voxels.iter().map(|v| v.tfce_value).collect()
+}
 
-            prev_value = value;
-            prev_tfce_value = tfce_value;
-            sz -= 1;
+// signed tfce maps of statistic for every (E, H) pair; cluster trees of
+// positive and negative part are built once and shared by all pairs
+pub fn enhance_statistic_batch(voxels: &mut Vec<Voxel>, statistic: Vec<f64>, tail: Tail, parameters: &[(f64, f64)]) -> Vec<Vec<f64>> {
+    let mut tfce_values = vec![vec![0.0; voxels.len()]; parameters.len()];
+    for &(sign, included) in [(1.0, tail.includes_positive()), (-1.0, tail.includes_negative())].iter() {
+        if !included {
+            continue;
         }
-        if let Some(box cluster) = cluster.parent_cluster_1 {
-            cluster_stack.push((cluster, prev_value, prev_tfce_value));
-        }
-        if let Some(box cluster) = cluster.parent_cluster_2 {
-            cluster_stack.push((cluster, prev_value, prev_tfce_value));
+        for (v, &sv) in voxels.iter_mut().zip(statistic.iter()) {
+            v.value = (sign * sv).max(0.0);
         }
+        COMPONENT_TREE.with(|tree| {
+            let mut tree = tree.borrow_mut();
+            tree.rebuild(voxels);
+            for (values, &(e, h)) in tfce_values.iter_mut().zip(parameters.iter()) {
+                tree.for_each_tfce(e, h, |vi, tfce_value| values[vi] += sign * tfce_value);
+            }
+        });
     }
+    for (v, sv) in voxels.iter_mut().zip(statistic.into_iter()) {
+        v.value = sv;
+    }
+    tfce_values
+}
+
+pub fn run_permutation<S: Statistic + ?Sized, E: Enhancement + ?Sized>(
+    voxels: &mut Vec<Voxel>,
+    a: &Vec<Vec<f64>>,
+    b: &Vec<Vec<f64>>,
+    statistic: &S,
+    permutations: &Permutations,
+    enhancement: &E,
+    tail: Tail,
+    alpha: f64
+) -> permutation::PermutationResult {
+    permutations.run(
+        &a, &b, alpha, statistic,
+        &mut |s| enhancement.enhance(voxels, s, tail)
+    )
 }
 
-fn find_max_voxel(voxels: &Vec<Voxel>) -> usize {
-    let mut max_i = 0;
-    let mut max_value = voxels[0].value;
+// observations are pooled in the order expected by scheme
+pub fn run_scheme_permutation<P: PermutationScheme + ?Sized, S: Statistic + ?Sized, E: Enhancement + ?Sized>(
+    voxels: &mut Vec<Voxel>,
+    observations: &Vec<Vec<f64>>,
+    scheme: &P,
+    statistic: &S,
+    enhancement: &E,
+    tail: Tail,
+    alpha: f64
+) -> permutation::PermutationResult {
+    permutation::run_scheme(
+        observations, scheme, alpha, statistic,
+        &mut |s| enhancement.enhance(voxels, s, tail)
+    )
+}
 
-    let mut i = 1;
-    while i < voxels.len() {
-        if voxels[i].value > max_value {
-            max_i = i;
-            max_value = voxels[i].value;
-        }
-        i += 1;
-    }
+pub fn run_one_sample_permutation<E: Enhancement + ?Sized>(
+    voxels: &mut Vec<Voxel>,
+    a: &Vec<Vec<f64>>,
+    popmean: f64,
+    sign_flips: &SignFlips,
+    enhancement: &E,
+    tail: Tail,
+    alpha: f64
+) -> permutation::PermutationResult {
+    permutation::run_one_sample_permutation(
+        &a, popmean, sign_flips, alpha, &OneSampleTTest { popmean },
+        &mut |s| enhancement.enhance(voxels, s, tail)
+    )
+}
 
-    max_i
+pub fn run_glm_permutation<E: Enhancement + ?Sized>(
+    voxels: &mut Vec<Voxel>,
+    glm: &Glm,
+    y: &Vec<Vec<f64>>,
+    permutations: &Permutations,
+    enhancement: &E,
+    tail: Tail,
+    alpha: f64
+) -> permutation::PermutationResult {
+    permutation::run_freedman_lane_permutation(
+        glm, &y, permutations, alpha,
+        &mut |s| enhancement.enhance(voxels, s, tail)
+    )
+}
+
+pub fn run_rm_anova_permutation<E: Enhancement + ?Sized>(
+    voxels: &mut Vec<Voxel>,
+    data: &Vec<Vec<Vec<f64>>>,
+    anova: RmAnova,
+    shuffles: &Vec<Vec<usize>>,
+    enhancement: &E,
+    alpha: f64
+) -> permutation::PermutationResult {
+    permutation::run_within_subject_permutation(
+        &data, shuffles, alpha, &anova,
+        &mut |s| enhancement.enhance(voxels, s, Tail::Positive)
+    )
 }
 
 fn traverse_max(from: usize, voxels: &Vec<Voxel>) -> Option<usize> {
@@ -1,8 +1,44 @@
 use super::*;
-use ::field::generate_1d_field;
+use ::field::{generate_1d_field, generate_2d8c_field};
 use ::field::set_random_values;
+use ::freesurfer::extend_graph_into_time;
 use test::Bencher;
 
+// nested view of component tree, easy to write down in tests
+#[derive(Debug, PartialEq)]
+struct Cluster {
+    voxel_indices: Vec<usize>,
+    size: usize,
+    extent: f64,
+    parent_cluster_1: Option<Box<Cluster>>,
+    parent_cluster_2: Option<Box<Cluster>>
+}
+
+fn build_cluster_tree(voxels: &Vec<Voxel>) -> Vec<Cluster> {
+    fn cluster(component: Component) -> Cluster {
+        let mut parents = component.parents().map(|c| Box::new(cluster(c)));
+        Cluster {
+            voxel_indices: component.voxels().to_vec(),
+            size: component.size(),
+            extent: component.extent(),
+            parent_cluster_1: parents.next(),
+            parent_cluster_2: parents.next()
+        }
+    }
+    ComponentTree::build(voxels).roots().map(cluster).collect()
+}
+
+// peaks and valleys both fall from left to right, so that every peak
+// merges into the cluster of all peaks left of it: tree depth is size / 2
+fn generate_staircase_field(size: usize) -> Vec<Voxel> {
+    let mut voxels = generate_1d_field(size);
+    for (i, v) in voxels.iter_mut().enumerate() {
+        let height = (size - i) as f64 / size as f64;
+        v.value = if i % 2 == 0 { height } else { height / 4.0 };
+    }
+    voxels
+}
+
 #[bench]
 fn benchmark_tfce(b: &mut Bencher) {
     let mut voxels = generate_1d_field(10000);
@@ -10,6 +46,34 @@ fn benchmark_tfce(b: &mut Bencher) {
     b.iter(|| tfce(&mut voxels, 2.0/3.0, 2.0));
 }
 
+#[bench]
+fn benchmark_tfce_1d_1m(b: &mut Bencher) {
+    let mut voxels = generate_1d_field(1000000);
+    set_random_values(&mut voxels, 0.1, 1.0, &[17556, 31771, 29830, 29830]);
+    b.iter(|| tfce(&mut voxels, 2.0/3.0, 2.0));
+}
+
+#[bench]
+fn benchmark_tfce_2d8c_1m(b: &mut Bencher) {
+    let mut voxels = generate_2d8c_field(1000);
+    set_random_values(&mut voxels, 0.1, 1.0, &[17556, 31771, 29830, 29830]);
+    b.iter(|| tfce(&mut voxels, 2.0/3.0, 2.0));
+}
+
+// 10^4 vertex mesh over 100 time points
+#[bench]
+fn benchmark_tfce_mesh_time_1m(b: &mut Bencher) {
+    let mut voxels = extend_graph_into_time(generate_2d8c_field(100), 100);
+    set_random_values(&mut voxels, 0.1, 1.0, &[17556, 31771, 29830, 29830]);
+    b.iter(|| tfce(&mut voxels, 2.0/3.0, 2.0));
+}
+
+#[bench]
+fn benchmark_tfce_deep_tree_1m(b: &mut Bencher) {
+    let mut voxels = generate_staircase_field(1000000);
+    b.iter(|| tfce(&mut voxels, 2.0/3.0, 2.0));
+}
+
 #[test]
 fn test_build_clusters_single_element() {
     let mut voxels = vec![
@@ -17,12 +81,13 @@ fn test_build_clusters_single_element() {
     ];
     assert_eq!(
         build_cluster_tree(&mut voxels),
-        Cluster {
+        vec![Cluster {
             voxel_indices: vec![0],
             size: 1,
+            extent: 1.0,
             parent_cluster_1: None,
             parent_cluster_2: None
-        }
+        }]
     );
 }
 
@@ -34,12 +99,13 @@ fn test_build_clusters_two_elements() {
     ];
     assert_eq!(
         build_cluster_tree(&mut voxels),
-        Cluster {
+        vec![Cluster {
             voxel_indices: vec![1, 0],
             size: 2,
+            extent: 2.0,
             parent_cluster_1: None,
             parent_cluster_2: None
-        }
+        }]
     );
 }
 
@@ -52,12 +118,13 @@ fn test_build_clusters_three_elements_linear() {
     ];
     assert_eq!(
         build_cluster_tree(&mut voxels),
-        Cluster {
+        vec![Cluster {
             voxel_indices: vec![2, 1, 0],
             size: 3,
+            extent: 3.0,
             parent_cluster_1: None,
             parent_cluster_2: None
-        }
+        }]
     );
 }
 
@@ -70,12 +137,13 @@ fn test_build_clusters_three_elements_hut() {
     ];
     assert_eq!(
         build_cluster_tree(&mut voxels),
-        Cluster {
+        vec![Cluster {
             voxel_indices: vec![1, 2, 0],
             size: 3,
+            extent: 3.0,
             parent_cluster_1: None,
             parent_cluster_2: None
-        }
+        }]
     );
 }
 
@@ -89,22 +157,25 @@ fn test_build_clusters_four_elements_linear_with_peak() {
     ];
     assert_eq!(
         build_cluster_tree(&mut voxels),
-        Cluster {
+        vec![Cluster {
             voxel_indices: vec![2],
             size: 4,
+            extent: 4.0,
             parent_cluster_1: Some(Box::new(Cluster {
                 voxel_indices: vec![0, 1],
                 size: 2,
+                extent: 2.0,
                 parent_cluster_1: None,
                 parent_cluster_2: None
             })),
             parent_cluster_2: Some(Box::new(Cluster {
                 voxel_indices: vec![3],
                 size: 1,
+                extent: 1.0,
                 parent_cluster_1: None,
                 parent_cluster_2: None
             }))
-        }
+        }]
     );
 }
 
@@ -119,21 +190,186 @@ fn test_build_clusters_five_elements_two_clusters() {
     ];
     assert_eq!(
         build_cluster_tree(&mut voxels),
-        Cluster {
+        vec![Cluster {
             voxel_indices: vec![2],
             size: 5,
+            extent: 5.0,
             parent_cluster_1: Some(Box::new(Cluster {
                 voxel_indices: vec![0, 1],
                 size: 2,
+                extent: 2.0,
                 parent_cluster_1: None,
                 parent_cluster_2: None
             })),
             parent_cluster_2: Some(Box::new(Cluster {
                 voxel_indices: vec![3, 4],
                 size: 2,
+                extent: 2.0,
                 parent_cluster_1: None,
                 parent_cluster_2: None
             }))
-        }
+        }]
+    );
+}
+
+#[test]
+fn test_signed_tfce_separates_tails() {
+    let mut voxels = generate_1d_field(6);
+    for (v, &value) in voxels.iter_mut().zip([0.0, 2.0, 3.0, -3.0, -2.0, 0.0].iter()) {
+        v.value = value;
+    }
+    signed_tfce(&mut voxels, Tail::TwoSided, 2.0/3.0, 2.0);
+    let tfce_values = voxels.iter().map(|v| v.tfce_value).collect::<Vec<f64>>();
+    assert!(tfce_values[1] > 0.0 && tfce_values[2] > tfce_values[1]);
+    for i in 0..6 {
+        assert!((tfce_values[i] + tfce_values[5 - i]).abs() < 1e-10);
+    }
+    assert_eq!(voxels[3].value, -3.0);
+
+    let mut abs_voxels = voxels.clone();
+    for v in abs_voxels.iter_mut() {
+        v.value = v.value.abs();
+    }
+    tfce(&mut abs_voxels, 2.0/3.0, 2.0);
+    assert!(abs_voxels[2].tfce_value > tfce_values[2]);
+
+    signed_tfce(&mut voxels, Tail::Negative, 2.0/3.0, 2.0);
+    assert_eq!(voxels[2].tfce_value, 0.0);
+    assert!((voxels[3].tfce_value + tfce_values[2]).abs() < 1e-10);
+}
+
+#[test]
+fn test_build_clusters_two_components() {
+    let mut voxels = vec![
+        Voxel::new(1.0, vec![1]),
+        Voxel::new(2.0, vec![0]),
+        Voxel::new(3.0, vec![3]),
+        Voxel::new(0.5, vec![2])
+    ];
+    assert_eq!(
+        build_cluster_tree(&mut voxels),
+        vec![
+            Cluster {
+                voxel_indices: vec![2, 3],
+                size: 2,
+                extent: 2.0,
+                parent_cluster_1: None,
+                parent_cluster_2: None
+            },
+            Cluster {
+                voxel_indices: vec![1, 0],
+                size: 2,
+                extent: 2.0,
+                parent_cluster_1: None,
+                parent_cluster_2: None
+            }
+        ]
     );
 }
+
+// two hemispheres side by side: every component is enhanced as if alone
+#[test]
+fn test_tfce_multiple_components() {
+    let mut lh = generate_1d_field(30);
+    set_random_values(&mut lh, 0.1, 1.0, &[17556, 31771, 29830, 29830]);
+    let mut rh = generate_2d8c_field(5);
+    set_random_values(&mut rh, 0.1, 2.0, &[17556, 31771, 29830, 29831]);
+    let isolated = Voxel::new(0.7, Vec::new());
+
+    let mut voxels = lh.clone();
+    for v in rh.iter() {
+        voxels.push(Voxel::new(v.value, v.voxel_links.iter().map(|l| l + lh.len()).collect()));
+    }
+    voxels.push(isolated.clone());
+
+    // values of earlier call must be replaced
+    for v in voxels.iter_mut() {
+        v.tfce_value = 100.0;
+    }
+    tfce(&mut voxels, 2.0/3.0, 2.0);
+    tfce(&mut lh, 2.0/3.0, 2.0);
+    tfce(&mut rh, 2.0/3.0, 2.0);
+
+    let expected = lh.iter().chain(rh.iter()).map(|v| v.tfce_value).collect::<Vec<f64>>();
+    for (v, x) in voxels.iter().zip(expected.iter()) {
+        assert!((v.tfce_value - x).abs() < 1e-10);
+    }
+    assert!((voxels[voxels.len() - 1].tfce_value - 0.7f64.powf(3.0) / 3.0).abs() < 1e-10);
+}
+
+#[test]
+fn test_tfce_resets_zero_components() {
+    let mut voxels = vec![
+        Voxel::new(2.0, vec![1]),
+        Voxel::new(1.0, vec![0]),
+        Voxel::new(0.0, vec![3]),
+        Voxel::new(0.0, vec![2])
+    ];
+    for v in voxels.iter_mut() {
+        v.tfce_value = 5.0;
+    }
+    tfce(&mut voxels, 2.0/3.0, 2.0);
+    assert!(voxels[0].tfce_value > voxels[1].tfce_value && voxels[1].tfce_value > 0.0);
+    assert_eq!(voxels[2].tfce_value, 0.0);
+    assert_eq!(voxels[3].tfce_value, 0.0);
+}
+
+#[test]
+fn test_tfce_weighted_extent() {
+    // isolated voxel with weight w has extent w instead of 1
+    let mut voxels = vec![Voxel::new(2.0, Vec::new())];
+    voxels[0].weight = 3.0;
+    tfce(&mut voxels, 0.5, 2.0);
+    assert!((voxels[0].tfce_value - 3.0f64.sqrt() * 8.0 / 3.0).abs() < 1e-10);
+
+    // exact and approximate tfce sum the same weights
+    let mut voxels = generate_2d8c_field(6);
+    set_random_values(&mut voxels, 0.1, 1.0, &[17556, 31771, 29830, 29830]);
+    for (i, v) in voxels.iter_mut().enumerate() {
+        v.weight = 0.5 + (i % 4) as f64;
+    }
+    let mut approx_voxels = voxels.clone();
+    ::tfce::approximate_tfce::approximate_tfce(&mut approx_voxels, 2.0/3.0, 2.0, 0.0, ::tfce::approximate_tfce::Discretisation::Steps(10000));
+    tfce(&mut voxels, 2.0/3.0, 2.0);
+    for (v, a) in voxels.iter().zip(approx_voxels.iter()) {
+        assert!((v.tfce_value - a.tfce_value).abs() < 1e-3 * v.tfce_value.max(1.0));
+    }
+
+    // unit weights give voxel count
+    let mut unit = generate_2d8c_field(6);
+    set_random_values(&mut unit, 0.1, 1.0, &[17556, 31771, 29830, 29830]);
+    tfce(&mut unit, 2.0/3.0, 2.0);
+    assert!(voxels.iter().zip(unit.iter()).any(|(v, u)| (v.tfce_value - u.tfce_value).abs() > 1e-3));
+}
+
+#[test]
+fn test_enhance_statistic_batch() {
+    let mut voxels = generate_2d8c_field(6);
+    set_random_values(&mut voxels, -1.0, 1.0, &[17556, 31771, 29830, 29830]);
+    let statistic = voxels.iter().map(|v| v.value).collect::<Vec<f64>>();
+    let parameters = vec![(2.0/3.0, 2.0), (0.5, 2.0), (1.9, 0.0)];
+    for &tail in [Tail::TwoSided, Tail::Positive, Tail::Negative].iter() {
+        let batch = enhance_statistic_batch(&mut voxels, statistic.clone(), tail, &parameters);
+        for (&(e, h), values) in parameters.iter().zip(batch.iter()) {
+            assert_eq!(values, &enhance_statistic(&mut voxels.clone(), statistic.clone(), tail, e, h));
+        }
+    }
+    assert_eq!(voxels.iter().map(|v| v.value).collect::<Vec<f64>>(), statistic);
+}
+
+// chain of 5 * 10^4 merges; the 10^6 voxel case is benchmark_tfce_deep_tree_1m
+#[test]
+fn test_tfce_deep_tree() {
+    let size = 100000;
+    let mut voxels = generate_staircase_field(size);
+    let tree = ComponentTree::build(&voxels);
+    assert_eq!(tree.roots().count(), 1);
+    assert_eq!(tree.len(), size - 1);
+
+    // above the valley to its left every peak is a cluster of its own
+    tfce(&mut voxels, 1.0, 0.0);
+    for i in (2..size).filter(|i| i % 2 == 0) {
+        let peak = voxels[i].value - voxels[i - 1].value;
+        assert!((voxels[i].tfce_value - voxels[i - 1].tfce_value - peak).abs() < 1e-6);
+    }
+}
@@ -1,3 +1,45 @@
+use ::statistic::Statistic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TTest {
+    Paired,
+    Student,
+    Welch
+}
+
+impl TTest {
+    pub fn compute(&self, a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>) -> Vec<f64> {
+        match *self {
+            TTest::Paired => ttest_rel_vec(a, b),
+            TTest::Student => ttest_ind_vec(a, b, true),
+            TTest::Welch => ttest_ind_vec(a, b, false)
+        }
+    }
+
+    pub fn is_paired(&self) -> bool {
+        *self == TTest::Paired
+    }
+}
+
+// groups are conditions (paired) or groups of subjects (independent)
+impl Statistic for TTest {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        TTest::compute(self, &groups[0], &groups[1])
+    }
+}
+
+// single group of subjects against popmean
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OneSampleTTest {
+    pub popmean: f64
+}
+
+impl Statistic for OneSampleTTest {
+    fn compute(&self, groups: &Vec<Vec<&Vec<f64>>>) -> Vec<f64> {
+        ttest_1samp_vec(&groups[0], self.popmean)
+    }
+}
+
 pub fn ttest_rel(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
     let nsubj = a.len();
     let mut sum = 0.0;
@@ -39,6 +81,81 @@ pub fn ttest_rel_vec(a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>) -> Vec<f64> {
     result
 }
 
+pub fn ttest_1samp(a: &Vec<f64>, popmean: f64) -> f64 {
+    let a = a.iter().map(|&v| vec![v]).collect::<Vec<Vec<f64>>>();
+    ttest_1samp_vec(&a.iter().collect(), popmean)[0]
+}
+
+pub fn ttest_1samp_vec(a: &Vec<&Vec<f64>>, popmean: f64) -> Vec<f64> {
+    let nsubj = a.len();
+    let n = a[0].len();
+
+    let mut result = Vec::with_capacity(n);
+    let mut i = 0;
+    while i < n {
+        let mut s = 0;
+        let mut sum = 0.0;
+        let mut sum2 = 0.0;
+        while s < nsubj {
+            let v = unsafe { a.get_unchecked(s).get_unchecked(i) - popmean };
+            sum += v;
+            sum2 += v*v;
+            s += 1;
+        }
+        let t_value = sum / ((sum2*(nsubj as f64) - sum*sum)/((nsubj - 1) as f64)).sqrt();
+        result.push(if t_value.is_nan() { 0.0 } else { t_value });
+
+        i += 1;
+    }
+    result
+}
+
+pub fn ttest_ind(a: &Vec<f64>, b: &Vec<f64>, equal_var: bool) -> f64 {
+    let a = a.iter().map(|&v| vec![v]).collect::<Vec<Vec<f64>>>();
+    let b = b.iter().map(|&v| vec![v]).collect::<Vec<Vec<f64>>>();
+    ttest_ind_vec(&a.iter().collect(), &b.iter().collect(), equal_var)[0]
+}
+
+pub fn ttest_ind_vec(a: &Vec<&Vec<f64>>, b: &Vec<&Vec<f64>>, equal_var: bool) -> Vec<f64> {
+    let na = a.len();
+    let nb = b.len();
+    let n = a[0].len();
+
+    let mut result = Vec::with_capacity(n);
+    let mut i = 0;
+    while i < n {
+        let (mean_a, var_a) = mean_var(a, i);
+        let (mean_b, var_b) = mean_var(b, i);
+        let se =
+            if equal_var {
+                let pooled_var = ((na - 1) as f64 * var_a + (nb - 1) as f64 * var_b) / ((na + nb - 2) as f64);
+                (pooled_var * (1.0 / (na as f64) + 1.0 / (nb as f64))).sqrt()
+            } else {
+                (var_a / (na as f64) + var_b / (nb as f64)).sqrt()
+            };
+        let t_value = (mean_a - mean_b) / se;
+        result.push(if t_value.is_nan() { 0.0 } else { t_value });
+
+        i += 1;
+    }
+    result
+}
+
+fn mean_var(x: &Vec<&Vec<f64>>, i: usize) -> (f64, f64) {
+    let nsubj = x.len();
+    let mut s = 0;
+    let mut sum = 0.0;
+    let mut sum2 = 0.0;
+    while s < nsubj {
+        let v = unsafe { *x.get_unchecked(s).get_unchecked(i) };
+        sum += v;
+        sum2 += v*v;
+        s += 1;
+    }
+    let mean = sum / (nsubj as f64);
+    (mean, (sum2 - sum * mean) / ((nsubj - 1) as f64))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,6 +177,26 @@ mod tests {
         assert!((ttest_rel(&a, &b) - -3.8813890034033038).abs() < 1e-7);
     }
 
+    #[test]
+    fn test_ttest_1samp() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645];
+        assert!((ttest_1samp(&a, 0.0) - 4.026216591697294).abs() < 1e-7);
+        assert!((ttest_1samp(&a, 0.5) - 0.33691870969549925).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_ttest_ind() {
+        let a = vec![ 0.78410583,  0.7053225 ,  0.04590954,  0.47383383,  0.71913645];
+        let b = vec![ 0.44289209,  0.60141457,  0.06801757,  0.23473256];
+        assert!((ttest_ind(&a, &b, true) - 1.1303421679637937).abs() < 1e-7);
+        assert!((ttest_ind(&a, &b, false) - 1.1672411889178853).abs() < 1e-7);
+
+        let a = vec![ 0.04378271,  0.26757753,  0.18631389,  0.38958731,  0.60529497,  0.1];
+        let b = vec![ 0.30417776,  0.88567478,  0.78889515,  0.5128209 ];
+        assert!((ttest_ind(&a, &b, true) - -2.4055181454889834).abs() < 1e-7);
+        assert!((ttest_ind(&a, &b, false) - -2.276888747517463).abs() < 1e-7);
+    }
+
     #[bench]
     fn benchmark_ttest(bench: &mut Bencher) {
         let mut rng = StdRng::from_seed(&[17556, 31771, 29830, 29830]);
@@ -1,8 +1,23 @@
+// dimension along which link connects voxels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    Space,
+    Time,
+    Frequency
+}
+
 #[derive(Debug, Clone)]
 pub struct Voxel {
     pub voxel_links: Vec<usize>,
     pub value: f64,
-    pub tfce_value: f64
+    pub tfce_value: f64,
+    // contribution to cluster extent, e.g. vertex area of decimated
+    // source space; 1 makes extent a voxel count
+    pub weight: f64,
+    // (linked voxel, dimension) of links that are not spatial; keyed by linked
+    // voxel rather than position, so links pushed to voxel_links directly stay
+    // spatial instead of shifting labels of other links
+    pub link_dimensions: Vec<(usize, Dimension)>
 }
 
 impl Voxel {
@@ -10,7 +25,33 @@ impl Voxel {
         Voxel {
             voxel_links: links,
             value,
-            tfce_value: 0.0
+            tfce_value: 0.0,
+            weight: 1.0,
+            link_dimensions: Vec::new()
+        }
+    }
+
+    // dimension of link to voxel index
+    pub fn link_dimension(&self, index: usize) -> Dimension {
+        self.link_dimensions.iter()
+            .find(|&&(i, _)| i == index)
+            .map_or(Dimension::Space, |&(_, dimension)| dimension)
+    }
+
+    pub fn add_link(&mut self, index: usize, dimension: Dimension) {
+        self.voxel_links.push(index);
+        if dimension != Dimension::Space {
+            self.link_dimensions.push((index, dimension));
+        }
+    }
+
+    // moves every link by offset, e.g. to a copy of the graph
+    pub fn offset_links(&mut self, offset: usize) {
+        for link in self.voxel_links.iter_mut() {
+            *link += offset;
+        }
+        for link in self.link_dimensions.iter_mut() {
+            link.0 += offset;
         }
     }
 }